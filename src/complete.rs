@@ -0,0 +1,298 @@
+//! Serve completion candidates to nu's external completer from the index of
+//! parsed completions written by `--lazy` or `build-index`.
+use std::collections::HashSet;
+
+use anyhow::Result;
+use log::{as_debug, debug, trace};
+use serde::Serialize;
+
+use crate::{
+    completion_line::CompletionLine, completions::split_shell_words, config::CompleteOptions,
+    index::IndexFile,
+};
+
+/// A completion candidate, in the form nu's external completer expects.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Candidate {
+    value: String,
+    description: Option<String>,
+}
+
+impl Candidate {
+    fn new(value: impl Into<String>, description: Option<&str>) -> Self {
+        Self {
+            value: value.into(),
+            description: description.map(str::to_string),
+        }
+    }
+}
+
+/// Split the command line being completed into words, the last of which is
+/// the word being completed (and is empty if the line ends with a space).
+/// Since the line is likely still being typed, an unterminated quote is
+/// allowed.
+fn tokenize(line: &str) -> Vec<String> {
+    let mut words = split_shell_words(line)
+        .or_else(|_| split_shell_words(&format!("{line}\"")))
+        .or_else(|_| split_shell_words(&format!("{line}'")))
+        .unwrap_or_else(|_| line.split_whitespace().map(str::to_string).collect());
+    if line.is_empty() || line.ends_with(char::is_whitespace) {
+        words.push(String::new());
+    }
+    words
+}
+
+/// The names under which fish may define completions for the subcommand `sub`
+/// of `command`.
+fn subcommand_keys(command: &str, sub: &str) -> [String; 2] {
+    [format!("{command} {sub}"), format!("{command}-{sub}")]
+}
+
+/// Evaluate the fish conditions which are commonly used to limit completions
+/// to certain subcommands, given the words preceding the one being completed.
+/// Any other condition can't be evaluated without fish, so it's assumed to be
+/// false.
+fn condition_holds(condition: Option<&str>, preceding: &[String]) -> bool {
+    let Some(condition) = condition else {
+        return true;
+    };
+    let Ok(words) = split_shell_words(condition) else {
+        return false;
+    };
+    let (negated, words) = match words.split_first() {
+        Some((not, rest)) if not == "not" => (true, rest),
+        _ => (false, words.as_slice()),
+    };
+    let arguments = || {
+        preceding
+            .iter()
+            .skip(1)
+            .filter(|word| !word.starts_with('-'))
+    };
+    let holds = match words {
+        [function] if function == "__fish_use_subcommand" || function == "__fish_is_first_arg" => {
+            arguments().next().is_none()
+        }
+        [function, subcommands @ ..] if function == "__fish_seen_subcommand_from" => {
+            arguments().any(|word| subcommands.contains(word))
+        }
+        _ => {
+            trace!(condition = condition; "can't evaluate condition");
+            return false;
+        }
+    };
+    holds != negated
+}
+
+/// The completion candidates for the given command line words, the last of
+/// which is the word being completed and is used to filter the candidates:
+///
+///  - the flags of the command, if the word starts with a dash, except those
+///    excluded by the flags already given
+///  - the values for the preceding flag, if it takes one
+///  - otherwise, the command's subcommands and any positional candidates
+///
+/// Candidates are only listed once, even if they're defined more than once.
+pub(crate) fn candidates(index: &IndexFile, words: &[String]) -> Result<Vec<Candidate>> {
+    let [command, preceding @ .., prefix] = words else {
+        return Ok(vec![]);
+    };
+    // Find the most specific (sub)command which is defined.
+    let mut command = command.clone();
+    for word in preceding {
+        for key in subcommand_keys(&command, word) {
+            if index.contains(&key)? {
+                command = key;
+                break;
+            }
+        }
+    }
+    let Some(completions) = index.get(&command)? else {
+        debug!(command = command; "command not in index");
+        return Ok(vec![]);
+    };
+    let mut preceding_words = vec![command.clone()];
+    preceding_words.extend(preceding.iter().cloned());
+    let applicable = completions
+        .iter()
+        .filter(|completion| condition_holds(completion.condition.as_deref(), &preceding_words));
+    let flags = |completion: &CompletionLine| {
+        completion
+            .long
+            .iter()
+            .map(|opt| format!("--{opt}"))
+            .chain(completion.short.iter().map(|opt| format!("-{opt}")))
+            .chain(completion.old_option.iter().map(|opt| format!("-{opt}")))
+            .collect::<Vec<_>>()
+    };
+    let arguments = |completion: &CompletionLine| {
+        let description = completion.description.as_deref();
+        completion
+            .values()
+            .into_iter()
+            .map(|argument| Candidate::new(argument, description))
+            .collect::<Vec<_>>()
+    };
+
+    let candidates: Vec<Candidate> = if prefix.starts_with('-') {
+        // Leave out the options excluded by those already given.
+        let excluded: HashSet<&str> = completions
+            .iter()
+            .filter(|completion| {
+                flags(completion)
+                    .iter()
+                    .any(|flag| preceding.contains(flag))
+            })
+            .flat_map(|completion| completion.exclusions.iter().map(String::as_str))
+            .collect();
+        if excluded.contains("-") {
+            return Ok(vec![]);
+        }
+        applicable
+            .filter(|completion| {
+                !flags(completion)
+                    .iter()
+                    .any(|flag| excluded.contains(flag.as_str()))
+            })
+            .flat_map(|completion| {
+                flags(completion)
+                    .into_iter()
+                    .map(|flag| Candidate::new(flag, completion.description.as_deref()))
+            })
+            .collect()
+    } else if let Some(flag) = preceding.last()
+        && let Some(completion) = completions
+            .iter()
+            .find(|completion| flags(completion).contains(flag))
+        && completion.takes_value()
+    {
+        arguments(completion)
+    } else {
+        let mut subcommands = vec![];
+        for prefix in [format!("{command} "), format!("{command}-")] {
+            for key in index.commands_starting_with(&prefix)? {
+                if let Some(sub) = key[prefix.len()..].split(' ').next() {
+                    subcommands.push(Candidate::new(sub, None));
+                }
+            }
+        }
+        let positional = applicable
+            .filter(|completion| flags(completion).is_empty())
+            .flat_map(arguments);
+        // Prefer the positional candidates, as they may have a description.
+        positional.chain(subcommands).collect()
+    };
+    let mut seen = HashSet::new();
+    Ok(candidates
+        .into_iter()
+        .filter(|candidate| candidate.value.starts_with(prefix.as_str()))
+        .filter(|candidate| seen.insert(candidate.value.clone()))
+        .collect())
+}
+
+/// Print the candidates for the command line in `opts` as JSON. If there are
+/// none, `null` is printed, so that nu falls back to its own completions.
+pub(crate) fn complete(opts: &CompleteOptions) -> Result<()> {
+    let index = IndexFile::open(&opts.index.index)?;
+    // nu passes the spans of the command line as they were typed (quotes
+    // included), so they can be joined back together and split as fish would.
+    let words = tokenize(&opts.args.join(" "));
+    let candidates = candidates(&index, &words)?;
+    debug!(words = as_debug!(words), count = candidates.len(); "found candidates");
+    if candidates.is_empty() {
+        println!("null");
+    } else {
+        println!("{}", serde_json::to_string(&candidates)?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{write_index, Index};
+    use tempfile::{tempdir, TempDir};
+
+    fn index() -> (TempDir, IndexFile) {
+        let line =
+            |command: &str, long: &[&str], argument: Option<&str>, condition: Option<&str>| {
+                CompletionLine {
+                    long: long.iter().map(|it| it.to_string()).collect(),
+                    command: Some(command.into()),
+                    argument: argument.map(str::to_string),
+                    condition: condition.map(str::to_string),
+                    description: Some(format!("{command} {long:?}")),
+                    ..Default::default()
+                }
+            };
+        let mut index = Index::new();
+        index.insert(
+            "mockery".into(),
+            vec![
+                line("mockery", &["all"], None, None),
+                line("mockery", &["color"], Some("auto always never"), None),
+                line("mockery", &[], Some("run"), Some("__fish_use_subcommand")),
+                line(
+                    "mockery",
+                    &["now"],
+                    None,
+                    Some("__fish_seen_subcommand_from run"),
+                ),
+            ],
+        );
+        index
+            .get_mut("mockery")
+            .expect("mockery")
+            .push(CompletionLine {
+                short: vec!["q".into()],
+                command: Some("mockery".into()),
+                exclusions: vec!["-q".into(), "--all".into()],
+                ..Default::default()
+            });
+        index.insert(
+            "mockery-build".into(),
+            vec![line("mockery-build", &["release"], None, None)],
+        );
+        let dir = tempdir().expect("temporary directory");
+        let path = dir.path().join("index.bin");
+        write_index(&index, &path).expect("index to be written");
+        (dir, IndexFile::open(&path).expect("index to be readable"))
+    }
+
+    fn candidates(line: &str) -> Vec<Candidate> {
+        super::candidates(&index().1, &tokenize(line)).expect("index lookup")
+    }
+
+    fn values(line: &str) -> Vec<String> {
+        candidates(line)
+            .into_iter()
+            .map(|candidate| candidate.value)
+            .collect()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize("git commit -m \"fo"),
+            ["git", "commit", "-m", "fo"]
+        );
+        assert_eq!(tokenize("git "), ["git", ""]);
+    }
+
+    #[test]
+    fn test_candidates() {
+        assert_eq!(values("mockery --a"), ["--all"]);
+        assert_eq!(values("mockery -"), ["--all", "--color", "-q"]);
+        assert_eq!(values("mockery --color a"), ["auto", "always"]);
+        assert_eq!(values("mockery "), ["run", "build"]);
+        assert_eq!(
+            candidates("mockery r"),
+            [Candidate::new("run", Some("mockery []"))]
+        );
+        assert_eq!(values("mockery run --"), ["--all", "--color", "--now"]);
+        assert_eq!(values("mockery build --"), ["--release"]);
+        assert_eq!(values("mockery -q -"), ["--color"]);
+        assert!(values("other -").is_empty());
+        assert!(values("mockery").is_empty());
+    }
+}
@@ -2,10 +2,12 @@ use std::borrow::Cow;
 
 use clap::Parser;
 use lazy_regex::regex_replace_all;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+
+use crate::completions::split_shell_words;
 
 /// A Fish completion definition
-#[derive(Debug, Parser, Clone, Default, Serialize)]
+#[derive(Debug, Parser, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) struct CompletionLine {
     /// Short options (-e -g)
     #[arg(short, long)]
@@ -25,9 +27,104 @@ pub(crate) struct CompletionLine {
     /// Old-style long options (-like -this)
     #[arg(short, long)]
     pub(crate) old_option: Vec<String>,
+    /// A fish command which must succeed for this completion to apply
+    #[arg(short = 'n', long)]
+    pub(crate) condition: Option<String>,
+    /// Files shouldn't be completed as arguments
+    #[arg(short = 'f', long)]
+    pub(crate) no_files: bool,
+    /// Files should be completed as arguments, even if another completion
+    /// for the command says they shouldn't be
+    #[arg(short = 'F', long)]
+    #[serde(default)]
+    pub(crate) force_files: bool,
+    /// The option must be given an argument
+    #[arg(short, long)]
+    pub(crate) require_parameter: bool,
+    /// Shorthand for both `--no-files` and `--require-parameter`
+    #[arg(short = 'x', long)]
+    pub(crate) exclusive: bool,
+    /// The arguments shouldn't be sorted
+    #[arg(short, long)]
+    pub(crate) keep_order: bool,
+    /// The options which can't be given after this one, as they're typed
+    /// (like `--verbose`), where `-` means every option. Fish has no way to
+    /// say this, but zsh does.
+    #[arg(skip)]
+    #[serde(default)]
+    pub(crate) exclusions: Vec<String>,
+    /// The nu type of the option's value (like `path`), when it's known.
+    /// Fish has no way to say this.
+    #[arg(skip)]
+    #[serde(default)]
+    pub(crate) value_type: Option<String>,
+    /// The commands this one wraps, whose completions it inherits (like
+    /// `ls -l` for `complete -c ll --wraps 'ls -l'`)
+    #[arg(short, long)]
+    #[serde(default)]
+    pub(crate) wraps: Vec<String>,
 }
 
 impl CompletionLine {
+    /// Whether this completion defines an option which takes a value.
+    pub(crate) fn takes_value(&self) -> bool {
+        self.argument.is_some() || self.require_parameter || self.exclusive
+    }
+
+    /// The nu type this option's value is given in an extern, if it takes
+    /// one: its [`CompletionLine::value_type`], or a string otherwise.
+    pub(crate) fn nu_type(&self) -> Option<&str> {
+        self.value_type
+            .as_deref()
+            .or_else(|| self.takes_value().then_some("string"))
+    }
+
+    /// The values listed in the argument, leaving out any which are the
+    /// output of a fish command (in parentheses).
+    pub(crate) fn values(&self) -> Vec<String> {
+        self.candidates()
+            .into_iter()
+            .map(|(value, _)| value)
+            .collect()
+    }
+
+    /// The values listed in the argument (see [`CompletionLine::values`]),
+    /// along with their own descriptions, which fish lists after a tab (like
+    /// `json\tJSON output`).
+    pub(crate) fn candidates(&self) -> Vec<(String, Option<String>)> {
+        self.argument
+            .as_deref()
+            .and_then(|argument| split_shell_words(argument).ok())
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|value| !value.starts_with('('))
+            .map(|value| match value.split_once('\t') {
+                Some((value, description)) => (value.to_string(), Some(description.to_string())),
+                None => (value, None),
+            })
+            .collect()
+    }
+
+    /// The names of the commands this completion's command wraps, without
+    /// the arguments they're wrapped with.
+    pub(crate) fn wrapped(&self) -> impl Iterator<Item = &str> {
+        self.wraps
+            .iter()
+            .filter_map(|it| it.split_whitespace().next())
+    }
+
+    /// Whether this completion and `other` define any of the same options
+    /// under the same condition.
+    pub(crate) fn overlaps(&self, other: &Self) -> bool {
+        fn shared(a: &[String], b: &[String]) -> bool {
+            a.iter().any(|it| b.contains(it))
+        }
+        self.condition == other.condition
+            && (shared(&self.short, &other.short)
+                || shared(&self.long, &other.long)
+                || shared(&self.old_option, &other.old_option))
+    }
+
     /// Fish is somehow totally cool parsing a completion like
     ///
     /// ```fish
@@ -3,14 +3,36 @@ use std::sync::{Arc, RwLock};
 use anyhow::anyhow;
 use clap::Parser;
 use derive_deref::Deref;
-use log::{as_serde, error, info, trace};
+use log::{as_serde, error, info, trace, warn};
 
-use defaultmap::DefaultHashMap;
+use defaultmap::DefaultBTreeMap;
 
 use crate::{completion_line::CompletionLine, nu::INTERNAL_COMMANDS};
 
+/// Split a line of fish into words.
+pub(crate) fn split_shell_words(line: &str) -> Result<Vec<String>, shell_words::ParseError> {
+    // Fish uses backslash to escape quotes, whereas every other shell (and
+    // the shell_words crate) do this '"'"' thing. Also, there was at least
+    // one instance of \\' (two backslashes i.e. an escaped backslash and
+    // then an apostrophe), so we need to get rid of double-backslash
+    // instances and then put them back after we're done with the whole
+    // quotes issue.
+    shell_words::split(
+        &line
+            .replace("\\\\", "\u{FFFD}")
+            // note that this ---------^^^^^^^^ is a different escape
+            // character than the one used by
+            // CompletionLine::escape_options_which_start_with_a_dash
+            .replace("\\'", r#"'"'"'"#)
+            .replace('\u{FFFD}', "\\\\"),
+    )
+}
+
+/// The completions for each command, sorted by command name so that they're
+/// always written in the same order. The completions for each command are kept
+/// in the order they were defined in.
 #[derive(Debug, Default, Deref, Clone)]
-pub(crate) struct Completions(Arc<RwLock<DefaultHashMap<String, Vec<CompletionLine>>>>);
+pub(crate) struct Completions(Arc<RwLock<DefaultBTreeMap<String, Vec<CompletionLine>>>>);
 
 impl Completions {
     /// Construct a new [`Completions`] by parsing the given lines.
@@ -23,22 +45,7 @@ impl Completions {
     pub(crate) fn parse_one_completion(self, completion: impl AsRef<str>) -> anyhow::Result<()> {
         let completion_ref = completion.as_ref();
         let completion = CompletionLine::escape_options_which_start_with_a_dash(completion_ref);
-        // Fish uses backslash to escape quotes, whereas every other shell (and
-        // the shell_words crate) do this '"'"' thing. Also, there was at least
-        // one instance of \\' (two backslashes i.e. an escaped backslash and
-        // then an apostrophe), so we need to get rid of double-backslash
-        // instances and then put them back after we're done with the whole
-        // quotes issue.
-        let args = shell_words::split(
-            &completion
-                .replace("\\\\", "\u{FFFD}")
-                // note that this ---------^^^^^^^^ is a different escape
-                // character than the one used by
-                // CompletionLine::escape_options_which_start_with_a_dash
-                .replace("\\'", r#"'"'"'"#)
-                .replace('\u{FFFD}', "\\\\"),
-        )
-        .map_err(|err| {
+        let args = split_shell_words(&completion).map_err(|err| {
             error!(line = completion; "error parsing shell words");
             err
         })?;
@@ -47,13 +54,11 @@ impl Completions {
             error!(line = completion; "error parsing completion line");
             err
         })?;
-        let Some(command_name) = &completion.command else {
+        if completion.command.is_none() {
             error!(completion = as_serde!(completion), line_text = completion_ref; "completion contained no command name");
-            return Err(anyhow!("completion contained no command name: {completion:?}"));
-        };
-        if INTERNAL_COMMANDS.contains(command_name) {
-            info!(command = command_name; "skipping internal command");
-            return Ok(());
+            return Err(anyhow!(
+                "completion contained no command name: {completion:?}"
+            ));
         }
         completion.description = completion
             .description
@@ -76,7 +81,14 @@ impl Completions {
             .iter()
             .map(CompletionLine::unescape_option_which_starts_with_a_dash)
             .collect();
+        self.add(completion)
+    }
 
+    /// Add a [`CompletionLine`] to the completions for its command.
+    pub(crate) fn add(&self, completion: CompletionLine) -> anyhow::Result<()> {
+        let Some(command_name) = &completion.command else {
+            return Err(anyhow!("completion contained no command name: {completion:?}"));
+        };
         self.0
             .write()
             .expect("poisoned mutex")
@@ -85,6 +97,137 @@ impl Completions {
         Ok(())
     }
 
+    /// Remove the completions for every command for which `keep` returns
+    /// false.
+    pub(crate) fn retain_commands(&self, mut keep: impl FnMut(&str) -> bool) {
+        let mut completions = self.0.write().expect("poisoned mutex");
+        let removed: Vec<_> = completions
+            .keys()
+            .filter(|command| !keep(command))
+            .cloned()
+            .collect();
+        for command in removed {
+            completions.remove(&command);
+        }
+    }
+
+    /// Remove the completions for nu's internal commands, which can't be
+    /// replaced by an extern. They're kept until now so that the commands
+    /// which wrap them can inherit their completions.
+    pub(crate) fn remove_internal_commands(&self) {
+        self.retain_commands(|command| {
+            let internal = INTERNAL_COMMANDS.iter().any(|it| it == command);
+            if internal {
+                info!(command = command; "skipping internal command");
+            }
+            !internal
+        });
+    }
+
+    /// Add the completions of the commands wrapped by each command (see
+    /// [`CompletionLine::wraps`]) to those of the command, and its
+    /// subcommands to the command's. The completions of a wrapped command
+    /// which isn't in these are found with `lookup`, if it can find them.
+    pub(crate) fn resolve_wraps(
+        &self,
+        lookup: &mut dyn FnMut(&str) -> anyhow::Result<Option<Completions>>,
+    ) -> anyhow::Result<()> {
+        let commands: Vec<_> = self
+            .0
+            .read()
+            .expect("poisoned mutex")
+            .keys()
+            .cloned()
+            .collect();
+        for command in commands {
+            self.resolve_command(&command, &mut vec![], lookup)?;
+        }
+        Ok(())
+    }
+
+    /// Resolve the commands wrapped by `command`, which is wrapped by each of
+    /// the commands in `wrapping`, in turn.
+    fn resolve_command(
+        &self,
+        command: &str,
+        wrapping: &mut Vec<String>,
+        lookup: &mut dyn FnMut(&str) -> anyhow::Result<Option<Completions>>,
+    ) -> anyhow::Result<()> {
+        let wrapped: Vec<String> = {
+            let completions = self.0.read().expect("poisoned mutex");
+            let mut wrapped: Vec<String> = vec![];
+            for name in completions
+                .get::<str, _>(command)
+                .iter()
+                .flat_map(CompletionLine::wrapped)
+            {
+                if !wrapped.iter().any(|it| it == name) {
+                    wrapped.push(name.to_string());
+                }
+            }
+            wrapped
+        };
+        if wrapped.is_empty() {
+            return Ok(());
+        }
+        wrapping.push(command.to_string());
+        for name in wrapped {
+            if wrapping.contains(&name) {
+                warn!(command = command, wraps = name, cycle = wrapping.join(" -> "); "skipping wrapped command which wraps this one");
+                continue;
+            }
+            let source = if self.0.read().expect("poisoned mutex").contains_key(&name) {
+                self.clone()
+            } else {
+                match lookup(&name)? {
+                    Some(found) => found,
+                    None => {
+                        info!(command = command, wraps = name; "no completions for wrapped command");
+                        continue;
+                    }
+                }
+            };
+            source.resolve_command(&name, wrapping, lookup)?;
+            let inherited = Completions::default();
+            for (wrapped_command, lines) in source.0.read().expect("poisoned mutex").iter() {
+                let subcommand = if wrapped_command == &name {
+                    ""
+                } else if let Some(subcommand) = wrapped_command.strip_prefix(&name) && subcommand.starts_with(' ') {
+                    subcommand
+                } else {
+                    continue;
+                };
+                let renamed = format!("{command}{subcommand}");
+                for line in lines.iter().filter(|it| it.wraps.is_empty()) {
+                    inherited.add(CompletionLine {
+                        command: Some(renamed.clone()),
+                        ..line.clone()
+                    })?;
+                }
+            }
+            self.merge(&inherited);
+        }
+        wrapping.pop();
+        Ok(())
+    }
+
+    /// Add the completions in `other`, which take lower precedence, to these.
+    /// Completions which are identical to, or define the same options as,
+    /// those already present are left out.
+    pub(crate) fn merge(&self, other: &Completions) {
+        let mut completions = self.0.write().expect("poisoned mutex");
+        for (command, lines) in other.0.read().expect("poisoned mutex").iter() {
+            let existing = completions.get_mut(command.clone());
+            for line in lines {
+                if existing.iter().any(|it| it == line || it.overlaps(line)) {
+                    trace!(command = command, completion = as_serde!(line); "skipping completion which is already defined");
+                    continue;
+                }
+                existing.push(line.clone());
+            }
+        }
+    }
+
     pub(crate) fn parse_completions(
         self,
         lines: impl Iterator<Item = impl AsRef<str>>,
@@ -137,4 +280,57 @@ mod test {
         assert!(completion.old_option.is_empty());
         Ok(())
     }
+
+    #[test]
+    fn test_merge() -> anyhow::Result<()> {
+        let user = Completions::parse(
+            ["complete -c mockery -s v -l verbose -d 'user description'"].into_iter(),
+        )?;
+        let vendor = Completions::parse(
+            [
+                "complete -c mockery -l verbose -d 'vendor description'",
+                "complete -c mockery -l quiet",
+                "complete -c mockery -l quiet",
+            ]
+            .into_iter(),
+        )?;
+        user.merge(&vendor);
+        let merged = &user.0.read().expect("poisoned Arc")[String::from("mockery")];
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].description.as_deref(), Some("user description"));
+        assert_eq!(merged[1].long, vec!["quiet"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_wraps() -> anyhow::Result<()> {
+        let completions = Completions::parse(
+            [
+                "complete -c g --wraps git",
+                "complete -c g -l global",
+                "complete -c a --wraps b",
+                "complete -c b --wraps a",
+                "complete -c b -s x",
+            ]
+            .into_iter(),
+        )?;
+        let git = Completions::parse(
+            [
+                "complete -c git -l global -d 'git description'",
+                "complete -c git -l version",
+                "complete -c 'git add' -s p",
+            ]
+            .into_iter(),
+        )?;
+        completions.resolve_wraps(&mut |command| Ok((command == "git").then(|| git.clone())))?;
+        let completions = completions.0.read().expect("poisoned Arc");
+        let g = &completions[String::from("g")];
+        assert_eq!(g.len(), 3);
+        assert_eq!(g[1].description, None);
+        assert_eq!(g[2].long, vec!["version"]);
+        assert_eq!(completions[String::from("g add")][0].short, vec!["p"]);
+        assert_eq!(completions[String::from("a")][1].short, vec!["x"]);
+        assert_eq!(completions[String::from("b")].len(), 2);
+        Ok(())
+    }
 }
@@ -2,12 +2,19 @@
 use std::{
     env,
     ffi::OsString,
+    num::NonZeroUsize,
     path::{Path, PathBuf},
+    process::{Command, Stdio},
     sync::LazyLock,
+    thread,
+    time::Duration,
 };
 
-use clap::{ArgAction, Args, Parser, Subcommand};
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
 use clap_verbosity_flag::Verbosity;
+use log::{as_debug, debug, warn};
+
+use crate::history;
 
 // Fish -> Nushell completion conversion script options
 #[derive(Debug, Parser)]
@@ -27,8 +34,34 @@ pub struct Config {
         default_value_os_t = xdg_config_path("nushell/completions/patches")
     )]
     pub patch_dir: PathBuf,
-    /// The original fish completion files to be converted
+    /// The original fish completion files to be converted. Defaults to the
+    /// directories fish searches for completions.
     pub sources: Vec<OsString>,
+    /// The format the sources are written in
+    #[arg(long, value_enum, default_value_t = InputFormat::Fish)]
+    pub input_format: InputFormat,
+    /// Read the sources as JSON documents of the completions, like those
+    /// printed by `dump-ir`, rather than in the --input-format
+    #[arg(long, conflicts_with = "input_format")]
+    pub from_ir: bool,
+    /// How many levels of subcommands to get the help for, with
+    /// `--input-format help` or `--query-cobra`
+    #[arg(long, default_value_t = 1)]
+    pub help_depth: usize,
+    /// How many seconds to wait for a command to print its help, with
+    /// `--input-format help` or `--query-cobra`
+    #[arg(long, default_value_t = 5)]
+    pub help_timeout: u64,
+    /// Run the commands whose fish completions were generated by cobra, which
+    /// only compute their completions while completing, to ask them for their
+    /// subcommands and flags
+    #[arg(long)]
+    pub query_cobra: bool,
+    /// Define an alias for each command which wraps another (like fish's
+    /// `function ll --wraps 'ls -l'`), which runs the command it wraps, after
+    /// its extern
+    #[arg(long)]
+    pub wrapper_aliases: bool,
     #[arg(
         long = "no-patch",
         action = ArgAction::SetFalse,
@@ -50,22 +83,129 @@ pub struct Config {
         help = "skip updating the standard patch-set from github"
     )]
     pub update_patch_set: bool,
+    /// Generate the definitions as a nu module (a `mod.nu` in the output
+    /// directory which `export use`s each definition), rather than sourcing
+    /// each definition file
+    #[arg(long)]
+    pub module: bool,
+    /// Concatenate all of the definitions into a single imports file, rather
+    /// than sourcing each definition file
+    #[arg(long, conflicts_with = "module")]
+    pub bundle: bool,
+    /// Rather than defining any externs, write an index of the completions
+    /// and an external completer which looks them up on demand
+    #[arg(long, conflicts_with_all = ["module", "bundle"])]
+    pub lazy: bool,
     #[command(subcommand)]
-    pub patches: Option<PatchesCommand>,
+    pub command: Option<Commands>,
     /// Add a line to the env config importing all of the defined completions
     #[arg(short, long)]
     pub install: bool,
     /// Where nushell's env.nu is located.
     #[arg(long, default_value_os_t = xdg_config_path("nushell/env.nu"))]
     pub install_location: PathBuf,
+    /// Place a file importing all of the defined completions in one of nu's
+    /// autoload directories, rather than editing env.nu
+    #[arg(long, conflicts_with = "install")]
+    pub autoload: bool,
+    /// The autoload directory to install into. If not specified, this is
+    /// discovered by asking nu, falling back to nu's default location.
+    #[arg(long, requires = "autoload")]
+    pub autoload_dir: Option<PathBuf>,
     #[arg(long)]
     pub fail_fast: bool,
+    /// Regenerate every definition, even those whose source and patch are
+    /// unchanged since the last run
+    #[arg(long)]
+    pub force: bool,
+    /// Leave definitions generated by previous runs in the output directory
+    /// when their source is removed, rather than deleting them
+    #[arg(long)]
+    pub keep_stale: bool,
+    /// The number of files to convert at once. Defaults to the number of
+    /// available CPUs.
+    #[arg(short, long)]
+    pub jobs: Option<NonZeroUsize>,
+    /// Skip generating definitions for commands which aren't installed (i.e.
+    /// can't be found in $PATH)
+    #[arg(long)]
+    pub only_installed: bool,
+    /// Search this PATH for installed commands rather than $PATH
+    #[arg(long, requires = "only_installed")]
+    pub installed_path: Option<OsString>,
+    /// Skip generating definitions for commands which were used fewer than
+    /// this many times according to nu's history
+    #[arg(long)]
+    pub min_uses: Option<usize>,
+    /// History files to count command uses from. Defaults to nu's
+    /// history.sqlite3 and history.txt, whichever exist.
+    #[arg(long, requires = "min_uses")]
+    pub history_file: Vec<PathBuf>,
+    /// Commands to always generate definitions for, regardless of how often
+    /// they were used
+    #[arg(long, requires = "min_uses", value_delimiter = ',')]
+    pub always_include: Vec<String>,
+}
+
+/// The format of the sources completions are generated from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum InputFormat {
+    /// Fish completion scripts
+    Fish,
+    /// Man pages, which may be gzipped
+    Man,
+    /// The output of `<command> --help`: either commands to run, or files
+    /// containing their help
+    Help,
+    /// Zsh completion functions, like `_git`
+    Zsh,
+    /// Bash completion scripts, from which only literal lists of words are
+    /// taken
+    Bash,
+    /// CLI specs in the `usage` format: KDL files of `cmd`, `flag` and `arg`
+    /// nodes
+    Usage,
+    /// Carapace specs: YAML files of a command's flags, subcommands and the
+    /// values they complete
+    Carapace,
+    /// Python scripts which use argparse, which are scanned rather than run
+    Argparse,
+    /// JSON documents of the completions, selected with `--from-ir`
+    #[value(skip)]
+    Ir,
+}
+
+/// How the generated definitions are made available to nu.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OutputMode {
+    /// The imports file sources each definition file.
+    Sourced,
+    /// The output directory is a module, which the imports file `use`s.
+    Module,
+    /// The imports file contains every definition.
+    Bundle,
+    /// The imports file sets an external completer which reads an index.
+    Lazy,
 }
 
 #[derive(Debug, Subcommand)]
-pub enum PatchesCommand {
+pub enum Commands {
     /// Commands related to patches
     Patches(PatchesSubCommand),
+    /// Print completion candidates for a command line as JSON, for use by
+    /// nu's external completer
+    Complete(CompleteOptions),
+    /// Parse completions into an index, without writing any definitions
+    BuildIndex(BuildIndexOptions),
+    /// Print the indexed completions for a command as JSON
+    Lookup(LookupOptions),
+    /// Print statistics about the index
+    Stats(IndexOptions),
+    /// Print the completions parsed from the sources as a JSON document, which
+    /// can be converted with `--from-ir`
+    DumpIr(DumpIrOptions),
+    /// Regenerate the definitions whenever the sources or patches change
+    Watch(WatchOptions),
 }
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Args, Debug)]
@@ -93,7 +233,64 @@ pub struct PatchesGenerateOptions {
     pub sources: Vec<OsString>,
 }
 
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Args, Debug)]
+pub struct IndexOptions {
+    /// The index written by `--lazy` or `build-index`
+    #[arg(long, default_value_os_t = xdg_config_path("nushell/completions/index.bin"))]
+    pub index: PathBuf,
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Args, Debug)]
+pub struct BuildIndexOptions {
+    #[command(flatten)]
+    pub index: IndexOptions,
+    /// The original fish completion files to be indexed. Defaults to the
+    /// directories fish searches for completions.
+    pub sources: Vec<OsString>,
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Args, Debug)]
+pub struct DumpIrOptions {
+    /// The sources to parse, in the --input-format. Defaults to the
+    /// directories they're usually found in.
+    pub sources: Vec<OsString>,
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Args, Debug)]
+pub struct WatchOptions {
+    /// How long to wait for changes to stop, in milliseconds, before
+    /// regenerating. Package managers tend to change many files at once.
+    #[arg(long, default_value_t = 500)]
+    pub debounce: u64,
+    /// The original fish completion files, or directories of them, to be
+    /// converted and watched. Defaults to the directories fish searches for
+    /// completions.
+    pub sources: Vec<OsString>,
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Args, Debug)]
+pub struct LookupOptions {
+    #[command(flatten)]
+    pub index: IndexOptions,
+    /// The command to look up
+    pub command: String,
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Args, Debug)]
+pub struct CompleteOptions {
+    #[command(flatten)]
+    pub index: IndexOptions,
+    /// The words of the command line being completed
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    pub args: Vec<String>,
+}
+
+#[cfg(not(test))]
 pub static CONFIG: LazyLock<Config> = LazyLock::new(Config::parse);
+/// The test binary's own arguments (like `--test-threads`) aren't ours, so
+/// tests which reach the config see the defaults.
+#[cfg(test)]
+pub static CONFIG: LazyLock<Config> = LazyLock::new(|| Config::parse_from(["nu-completions"]));
 
 impl Config {
     pub(crate) fn verbose() -> &'static Verbosity {
@@ -102,8 +299,68 @@ impl Config {
     pub(crate) fn output_dir() -> &'static Path {
         CONFIG.output_dir.as_path()
     }
-    pub(crate) fn sources() -> &'static Vec<OsString> {
-        &CONFIG.sources
+    pub(crate) fn sources() -> &'static [OsString] {
+        Self::sources_or_discovered(&CONFIG.sources)
+    }
+    /// The given `sources`, or if there are none, the directories the
+    /// sources are usually found in for the [`Config::input_format`]: the
+    /// directories fish searches for completions, in fish's order of
+    /// precedence, the man page sections for commands, zsh's function path,
+    /// the directories bash-completion loads completions from, or carapace's
+    /// user specs.
+    /// Commands to get the help for, usage specs, Python scripts and IR
+    /// documents are never discovered.
+    pub(crate) fn sources_or_discovered(sources: &[OsString]) -> &[OsString] {
+        static DISCOVERED: LazyLock<Vec<OsString>> = LazyLock::new(|| {
+            let dirs = match Config::input_format() {
+                InputFormat::Fish => discover_fish_complete_path(),
+                InputFormat::Man => discover_man_dirs(),
+                InputFormat::Zsh => discover_zsh_fpath(),
+                InputFormat::Bash => discover_bash_completion_dirs(),
+                InputFormat::Help => {
+                    warn!("pass the commands to get the help for, or files containing their help");
+                    vec![]
+                }
+                InputFormat::Carapace => discover_carapace_specs(),
+                InputFormat::Usage => {
+                    warn!("pass the usage specs to convert");
+                    vec![]
+                }
+                InputFormat::Argparse => {
+                    warn!("pass the Python scripts to convert");
+                    vec![]
+                }
+                InputFormat::Ir => {
+                    warn!("pass the IR documents to convert");
+                    vec![]
+                }
+            };
+            dirs.into_iter().map(PathBuf::into_os_string).collect()
+        });
+        if sources.is_empty() {
+            &DISCOVERED
+        } else {
+            sources
+        }
+    }
+    pub(crate) fn input_format() -> InputFormat {
+        if CONFIG.from_ir {
+            InputFormat::Ir
+        } else {
+            CONFIG.input_format
+        }
+    }
+    pub(crate) fn help_depth() -> usize {
+        CONFIG.help_depth
+    }
+    pub(crate) fn help_timeout() -> Duration {
+        Duration::from_secs(CONFIG.help_timeout)
+    }
+    pub(crate) fn query_cobra() -> bool {
+        CONFIG.query_cobra
+    }
+    pub(crate) fn wrapper_aliases() -> bool {
+        CONFIG.wrapper_aliases
     }
     pub(crate) fn patch_dir() -> &'static Path {
         CONFIG.patch_dir.as_path()
@@ -115,9 +372,87 @@ impl Config {
         CONFIG.convert
     }
 
+    pub(crate) fn output_mode() -> OutputMode {
+        if CONFIG.module {
+            OutputMode::Module
+        } else if CONFIG.bundle {
+            OutputMode::Bundle
+        } else if CONFIG.lazy {
+            OutputMode::Lazy
+        } else {
+            OutputMode::Sourced
+        }
+    }
+
     pub(crate) fn fail_fast() -> bool {
         CONFIG.fail_fast
     }
+
+    /// Whether definitions whose source and patch are unchanged since the
+    /// last run can be left as they are. Definitions are always regenerated
+    /// if `--force` was given, or if they depend on which commands are
+    /// installed or have been used.
+    pub(crate) fn incremental() -> bool {
+        !CONFIG.force && !CONFIG.only_installed && CONFIG.min_uses.is_none()
+    }
+
+    pub(crate) fn keep_stale() -> bool {
+        CONFIG.keep_stale
+    }
+
+    /// The options which affect the contents of the generated definitions,
+    /// recorded in the manifest so that changing them regenerates everything.
+    pub(crate) fn manifest_options() -> String {
+        format!(
+            "{:?}",
+            (
+                Self::input_format(),
+                Self::help_depth(),
+                Self::query_cobra(),
+                Self::wrapper_aliases(),
+                Self::installed_path(),
+                Self::min_uses(),
+                Self::always_include()
+            )
+        )
+    }
+
+    pub(crate) fn jobs() -> usize {
+        CONFIG
+            .jobs
+            .or_else(|| thread::available_parallelism().ok())
+            .map_or(1, NonZeroUsize::get)
+    }
+    /// The PATH which commands must be found in to have definitions
+    /// generated, if `--only-installed` was given.
+    pub(crate) fn installed_path() -> Option<OsString> {
+        if CONFIG.only_installed {
+            CONFIG
+                .installed_path
+                .clone()
+                .or_else(|| env::var_os("PATH"))
+                .or_else(|| Some(OsString::new()))
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn min_uses() -> Option<usize> {
+        CONFIG.min_uses
+    }
+
+    pub(crate) fn history_files() -> Vec<PathBuf> {
+        if CONFIG.history_file.is_empty() {
+            history::default_history_files(&xdg_config_path("nushell"))
+        } else {
+            CONFIG.history_file.clone()
+        }
+    }
+
+    pub(crate) fn always_include() -> &'static [String] {
+        &CONFIG.always_include
+    }
+
     pub(crate) fn update_patch_set() -> bool {
         CONFIG.update_patch_set
     }
@@ -130,12 +465,75 @@ impl Config {
         }
     }
 
+    /// The autoload directory to install into, if `--autoload` was given.
+    pub(crate) fn autoload() -> Option<PathBuf> {
+        if CONFIG.autoload {
+            Some(
+                CONFIG
+                    .autoload_dir
+                    .clone()
+                    .unwrap_or_else(discover_autoload_dir),
+            )
+        } else {
+            None
+        }
+    }
+
     pub(crate) fn generate_patches() -> Option<&'static PatchesGenerateOptions> {
-        CONFIG.patches.as_ref().map(|arg| {
-            let PatchesCommand::Patches(arg) = arg;
+        if let Some(Commands::Patches(arg)) = &CONFIG.command {
             let PatchesSubCommandAction::Generate(arg) = &arg.action;
-            arg
-        })
+            Some(arg)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn complete() -> Option<&'static CompleteOptions> {
+        if let Some(Commands::Complete(arg)) = &CONFIG.command {
+            Some(arg)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn build_index() -> Option<&'static BuildIndexOptions> {
+        if let Some(Commands::BuildIndex(arg)) = &CONFIG.command {
+            Some(arg)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn lookup() -> Option<&'static LookupOptions> {
+        if let Some(Commands::Lookup(arg)) = &CONFIG.command {
+            Some(arg)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn dump_ir() -> Option<&'static DumpIrOptions> {
+        if let Some(Commands::DumpIr(arg)) = &CONFIG.command {
+            Some(arg)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn watch() -> Option<&'static WatchOptions> {
+        if let Some(Commands::Watch(arg)) = &CONFIG.command {
+            Some(arg)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn stats() -> Option<&'static IndexOptions> {
+        if let Some(Commands::Stats(arg)) = &CONFIG.command {
+            Some(arg)
+        } else {
+            None
+        }
     }
 
     pub(crate) fn imports_location() -> PathBuf {
@@ -144,6 +542,224 @@ impl Config {
             .expect("config output dir has parent")
             .join("imports.nu")
     }
+
+    /// Where the index of parsed completions is written by `--lazy`.
+    pub(crate) fn index_location() -> PathBuf {
+        Self::output_dir()
+            .parent()
+            .expect("config output dir has parent")
+            .join("index.bin")
+    }
+}
+
+/// Ask nu where it looks for autoloaded files, preferring the user's autoload
+/// directories over the vendor ones. If nu can't tell us (for example, an
+/// older version without `$nu.user-autoload-dirs`), fall back to the default
+/// user autoload directory.
+fn discover_autoload_dir() -> PathBuf {
+    let output = Command::new("nu")
+        .arg("--no-config-file")
+        .arg("-c")
+        .arg("$nu.user-autoload-dirs | append $nu.vendor-autoload-dirs | str join (char nl)")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            let dirs = String::from_utf8_lossy(&output.stdout);
+            if let Some(dir) = dirs
+                .lines()
+                .map(str::trim)
+                .find(|dir| Path::new(dir).is_absolute())
+            {
+                debug!(dir = dir; "autoload directory discovered");
+                return PathBuf::from(dir);
+            }
+            warn!("nu reported no autoload directories");
+        }
+        Ok(output) => {
+            warn!(status = output.status.code(); "failed to query nu for autoload directories")
+        }
+        Err(err) => warn!(error = as_debug!(err); "failed to run nu"),
+    }
+    xdg_config_path("nushell/autoload")
+}
+
+/// Ask fish for the directories it searches for completions
+/// (`$fish_complete_path`). If fish isn't installed, fall back to the
+/// directories fish searches by default. Either way, only directories which
+/// exist are returned.
+fn discover_fish_complete_path() -> Vec<PathBuf> {
+    let output = Command::new("fish")
+        .arg("-c")
+        .arg("string join \n -- $fish_complete_path")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .output();
+    let dirs = match output {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::trim)
+            .filter(|dir| Path::new(dir).is_absolute())
+            .map(PathBuf::from)
+            .collect(),
+        Ok(output) => {
+            warn!(status = output.status.code(); "failed to query fish for its completion path");
+            default_fish_complete_path()
+        }
+        Err(err) => {
+            debug!(error = as_debug!(err); "failed to run fish");
+            default_fish_complete_path()
+        }
+    };
+    let dirs: Vec<_> = dirs.into_iter().filter(|dir| dir.is_dir()).collect();
+    debug!(dirs = as_debug!(dirs); "fish completion directories discovered");
+    if dirs.is_empty() {
+        warn!("no fish completion directories found, pass the fish completions to convert");
+    }
+    dirs
+}
+
+/// The directories containing the man pages for commands (sections 1 and
+/// 8), in the order `man` searches them. The search path comes from
+/// `manpath`, or `$MANPATH` if that isn't available, or the usual locations.
+fn discover_man_dirs() -> Vec<PathBuf> {
+    let output = Command::new("manpath")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .output();
+    let manpath = match output {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        }
+        _ => {
+            debug!("failed to run manpath");
+            env::var("MANPATH")
+                .ok()
+                .filter(|it| !it.trim_matches(':').is_empty())
+                .unwrap_or_else(|| "/usr/local/share/man:/usr/share/man".to_string())
+        }
+    };
+    let dirs: Vec<_> = manpath
+        .split(':')
+        .filter(|dir| !dir.is_empty())
+        .flat_map(|dir| ["man1", "man8"].map(|section| Path::new(dir).join(section)))
+        .filter(|dir| dir.is_dir())
+        .collect();
+    debug!(dirs = as_debug!(dirs); "man page directories discovered");
+    if dirs.is_empty() {
+        warn!("no man page directories found, pass the man pages to convert");
+    }
+    dirs
+}
+
+/// Ask zsh for the directories it searches for functions (`$fpath`), which
+/// include those containing its completion functions. If zsh isn't
+/// installed, fall back to the usual locations. Either way, only directories
+/// which exist are returned.
+fn discover_zsh_fpath() -> Vec<PathBuf> {
+    let output = Command::new("zsh")
+        .arg("-fc")
+        .arg("print -rl -- $fpath")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .output();
+    let dirs: Vec<PathBuf> = match output {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::trim)
+            .filter(|dir| Path::new(dir).is_absolute())
+            .map(PathBuf::from)
+            .collect(),
+        _ => {
+            debug!("failed to query zsh for its function path");
+            [
+                "/usr/local/share/zsh/site-functions",
+                "/usr/share/zsh/site-functions",
+                "/usr/share/zsh/vendor-completions",
+                "/usr/share/zsh/functions/Completion",
+            ]
+            .map(PathBuf::from)
+            .into()
+        }
+    };
+    let dirs: Vec<_> = dirs.into_iter().filter(|dir| dir.is_dir()).collect();
+    debug!(dirs = as_debug!(dirs); "zsh function directories discovered");
+    if dirs.is_empty() {
+        warn!("no zsh function directories found, pass the zsh completions to convert");
+    }
+    dirs
+}
+
+/// The directories bash-completion loads completions from, in the order it
+/// searches them, which exist.
+fn discover_bash_completion_dirs() -> Vec<PathBuf> {
+    let data_home = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")));
+    let dirs: Vec<_> = data_home
+        .into_iter()
+        .map(|dir| dir.join("bash-completion/completions"))
+        .chain(
+            [
+                "/usr/local/share/bash-completion/completions",
+                "/usr/share/bash-completion/completions",
+                "/etc/bash_completion.d",
+            ]
+            .map(PathBuf::from),
+        )
+        .filter(|dir| dir.is_dir())
+        .collect();
+    debug!(dirs = as_debug!(dirs); "bash completion directories discovered");
+    if dirs.is_empty() {
+        warn!("no bash completion directories found, pass the bash completions to convert");
+    }
+    dirs
+}
+
+/// The directory carapace loads the user's specs from, if it exists.
+fn discover_carapace_specs() -> Vec<PathBuf> {
+    let dir = xdg_config_path("carapace/specs");
+    debug!(dir = as_debug!(dir); "carapace specs directory");
+    if dir.is_dir() {
+        vec![dir]
+    } else {
+        warn!("no carapace specs found, pass the specs to convert");
+        vec![]
+    }
+}
+
+/// The directories fish searches for completions when `$fish_complete_path`
+/// hasn't been changed, in order.
+fn default_fish_complete_path() -> Vec<PathBuf> {
+    let data_home = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")));
+    let data_dirs = env::var("XDG_DATA_DIRS")
+        .ok()
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or_else(|| "/usr/local/share:/usr/share".to_string());
+    let mut dirs = vec![
+        xdg_config_path("fish/completions"),
+        PathBuf::from("/etc/fish/completions"),
+    ];
+    dirs.extend(
+        data_home
+            .iter()
+            .map(|dir| dir.join("fish/vendor_completions.d")),
+    );
+    dirs.extend(
+        data_dirs
+            .split(':')
+            .map(|dir| Path::new(dir).join("fish/vendor_completions.d")),
+    );
+    dirs.push(PathBuf::from("/usr/share/fish/completions"));
+    dirs.extend(
+        data_home
+            .iter()
+            .map(|dir| dir.join("fish/generated_completions")),
+    );
+    dirs
 }
 
 fn xdg_config_path(subpath: impl AsRef<Path>) -> PathBuf {
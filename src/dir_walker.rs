@@ -2,6 +2,7 @@ use anyhow::{anyhow, Result};
 use std::{
     fmt::Debug,
     path::{Path, PathBuf},
+    sync::Mutex,
 };
 
 /// Call the `callback` on each file in the given `path`, following symlinks.
@@ -19,13 +20,15 @@ where
         }
         Ok(())
     } else if path.is_symlink() {
-        walk_dir(
-            &path
-                .read_link()
-                .map_err(|e| anyhow!("error dereferencing symlink at {path:?}: {e:?}"))?,
-            extra_arg,
-            callback,
-        )
+        let target = path
+            .read_link()
+            .map_err(|e| anyhow!("error dereferencing symlink at {path:?}: {e:?}"))?;
+        // Relative links are relative to the directory containing the link.
+        let target = match path.parent() {
+            Some(parent) if target.is_relative() => parent.join(target),
+            _ => target,
+        };
+        walk_dir(&target, extra_arg, callback)
     } else if path.is_file() {
         callback(path.to_path_buf(), extra_arg)
     } else if path.exists() {
@@ -39,3 +42,16 @@ where
         .into())
     }
 }
+
+/// Every file in the given `path`, following symlinks, sorted so that the
+/// order doesn't depend on the filesystem.
+pub fn list_files(path: &Path) -> Result<Vec<PathBuf>> {
+    let files = Mutex::new(vec![]);
+    walk_dir(path, (), |file, _| {
+        files.lock().expect("poisoned mutex").push(file);
+        Ok(())
+    })?;
+    let mut files = files.into_inner().expect("poisoned mutex");
+    files.sort();
+    Ok(files)
+}
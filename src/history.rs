@@ -0,0 +1,168 @@
+//! Count how often commands are used according to nu's history, so that
+//! definitions can be limited to the commands which are actually used.
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, Result};
+use log::{as_debug, debug, info, trace, warn};
+use serde::Deserialize;
+
+/// The number of times each command appears in nu's history.
+#[derive(Debug, Default)]
+pub(crate) struct CommandUsage(HashMap<String, usize>);
+
+/// A row from the `history` table of nu's SQLite history.
+#[derive(Debug, Deserialize)]
+struct HistoryRow {
+    command_line: String,
+}
+
+impl CommandUsage {
+    /// Count the commands in each of the given history files. Files ending
+    /// in `.sqlite3` are read as nu's SQLite history format (using the
+    /// `sqlite3` command), anything else as plaintext history, one command
+    /// line per line. If none of them could be read (there were none, or
+    /// `sqlite3` isn't installed), `None` is returned, as there's nothing to
+    /// tell which commands are used.
+    pub(crate) fn load(files: &[PathBuf]) -> Result<Option<Self>> {
+        let mut usage = Self::default();
+        let mut read = 0;
+        for file in files {
+            info!(file = file.to_string_lossy(); "reading history");
+            if file.extension().map_or(false, |ext| ext == "sqlite3") {
+                if usage.count_sqlite(file)? {
+                    read += 1;
+                }
+            } else {
+                usage.count_plaintext(file)?;
+                read += 1;
+            }
+        }
+        if read == 0 {
+            return Ok(None);
+        }
+        debug!(commands = usage.0.len(); "history read");
+        Ok(Some(usage))
+    }
+
+    /// Count the commands in a plaintext (`history.txt`) history file.
+    fn count_plaintext(&mut self, file: &Path) -> Result<()> {
+        for line in BufReader::new(File::open(file)?).lines() {
+            self.count_line(&line?);
+        }
+        Ok(())
+    }
+
+    /// Count the commands in a SQLite (`history.sqlite3`) history file,
+    /// which is read with the `sqlite3` CLI. If that isn't installed, the
+    /// file is skipped, and `false` is returned.
+    fn count_sqlite(&mut self, file: &Path) -> Result<bool> {
+        let output = match Command::new("sqlite3")
+            .arg("-readonly")
+            .arg("-json")
+            .arg(file)
+            .arg("SELECT command_line FROM history")
+            .stdout(Stdio::piped())
+            .output()
+        {
+            Ok(output) => output,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                warn!(file = file.to_string_lossy(); "sqlite3 isn't installed, skipping history file");
+                return Ok(false);
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if !output.status.success() {
+            return Err(anyhow!(
+                "failed to read history from {file:?}: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        // sqlite3 prints nothing at all, rather than an empty array, when
+        // there are no rows.
+        if output.stdout.iter().all(u8::is_ascii_whitespace) {
+            return Ok(true);
+        }
+        let rows: Vec<HistoryRow> = serde_json::from_slice(&output.stdout)?;
+        for row in &rows {
+            self.count_line(&row.command_line);
+        }
+        Ok(true)
+    }
+
+    /// Count the commands run in a single command line. This doesn't attempt
+    /// to fully parse nu's syntax; the first word of each pipeline element is
+    /// counted as a command.
+    fn count_line(&mut self, line: &str) {
+        for element in line.split(['|', ';', '\n']) {
+            let Some(command) = element.split_whitespace().next() else {
+                continue;
+            };
+            let command = command
+                .trim_start_matches('^')
+                .trim_matches(|c| c == '"' || c == '\'' || c == '`');
+            if command.is_empty() {
+                continue;
+            }
+            trace!(command = command; "counting command use");
+            *self.0.entry(command.to_string()).or_default() += 1;
+        }
+    }
+
+    /// The number of times the given command was used. Fish defines the
+    /// completions for some subcommands under a dashed name (like `git-add`),
+    /// so those are counted as uses of the part before the first dash.
+    pub(crate) fn uses(&self, command: &str) -> usize {
+        let uses = self.0.get(command).copied().unwrap_or_default();
+        if uses == 0 && let Some((parent, _)) = command.split_once(['-', ' ']) {
+            trace!(command = command, parent = parent; "checking parent command usage");
+            self.0.get(parent).copied().unwrap_or_default()
+        } else {
+            uses
+        }
+    }
+}
+
+/// nu's history files which exist in the given config directory.
+pub(crate) fn default_history_files(nu_config_dir: &Path) -> Vec<PathBuf> {
+    let files: Vec<PathBuf> = ["history.sqlite3", "history.txt"]
+        .iter()
+        .map(|name| nu_config_dir.join(name))
+        .filter(|file| file.exists())
+        .collect();
+    debug!(files = as_debug!(files); "found history files");
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_line() {
+        let mut usage = CommandUsage::default();
+        usage.count_line("git status | lines; ^rg foo");
+        usage.count_line("git commit -m 'a | b'");
+        assert_eq!(usage.uses("git"), 2);
+        assert_eq!(usage.uses("rg"), 1);
+        assert_eq!(usage.uses("lines"), 1);
+        assert_eq!(usage.uses("git-add"), 2);
+        assert_eq!(usage.uses("cargo"), 0);
+    }
+
+    #[test]
+    fn test_load_without_history() -> Result<()> {
+        assert!(CommandUsage::load(&[])?.is_none());
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("history.txt");
+        std::fs::write(&file, "git status\n")?;
+        let usage = CommandUsage::load(&[file])?.expect("history to be read");
+        assert_eq!(usage.uses("git"), 1);
+        Ok(())
+    }
+}
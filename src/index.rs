@@ -0,0 +1,270 @@
+//! A compact index of parsed completions, which can be memory-mapped so that
+//! the completions for a single command can be looked up without reading (or
+//! parsing) everything else.
+//!
+//! ## Format
+//! All integers are little-endian `u32`s.
+//!
+//! | bytes           | contents                                           |
+//! |-----------------|----------------------------------------------------|
+//! | 8               | [`MAGIC`]                                          |
+//! | 4               | the format version, [`VERSION`]                    |
+//! | 4               | the number of commands                             |
+//! | 16 per command  | the offset and length of the command name, then the offset and length of its completions, sorted by command name |
+//! | remainder       | the command names and [`bincode`]-encoded completions |
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+use log::{as_debug, debug, trace};
+use memmap2::Mmap;
+
+use crate::{
+    completion_line::CompletionLine,
+    config::{IndexOptions, LookupOptions},
+};
+
+/// Every parsed completion, keyed by the command it completes.
+pub(crate) type Index = BTreeMap<String, Vec<CompletionLine>>;
+
+/// Identifies an index file.
+const MAGIC: &[u8; 8] = b"NUCMPIDX";
+/// The version of the format. This must be incremented whenever the format,
+/// or the fields of [`CompletionLine`], change.
+pub(crate) const VERSION: u32 = 5;
+const HEADER_LEN: usize = 16;
+const ENTRY_LEN: usize = 16;
+
+/// Write `index` to the file at `to`.
+pub(crate) fn write_index(index: &Index, to: &Path) -> Result<()> {
+    let count = u32::try_from(index.len())?;
+    let mut table = Vec::with_capacity(index.len() * ENTRY_LEN);
+    let mut data = vec![];
+    let data_start = HEADER_LEN + index.len() * ENTRY_LEN;
+    let mut push = |bytes: &[u8], table: &mut Vec<u8>| -> Result<()> {
+        let offset = u32::try_from(data_start + data.len())?;
+        table.extend(offset.to_le_bytes());
+        table.extend(u32::try_from(bytes.len())?.to_le_bytes());
+        data.extend_from_slice(bytes);
+        Ok(())
+    };
+    // BTreeMap iterates in order, so the table is sorted by command name.
+    for (command, completions) in index {
+        push(command.as_bytes(), &mut table)?;
+        push(&bincode::serialize(completions)?, &mut table)?;
+    }
+    let mut bytes = Vec::with_capacity(data_start + data.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend(VERSION.to_le_bytes());
+    bytes.extend(count.to_le_bytes());
+    bytes.extend(table);
+    bytes.extend(data);
+    fs::write(to, bytes)?;
+    debug!(location = as_debug!(to), commands = count; "index written");
+    Ok(())
+}
+
+/// An index file, memory-mapped for lookups.
+#[derive(Debug)]
+pub(crate) struct IndexFile {
+    map: Mmap,
+    count: usize,
+}
+
+impl IndexFile {
+    /// Map the index file at `path`, checking that it's an index of the
+    /// current version.
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        // Safety: the index isn't expected to be modified while it's being
+        // read. If it is, the worst case is a garbled lookup, since every
+        // offset is bounds-checked.
+        let map = unsafe { Mmap::map(&file)? };
+        if map.len() < HEADER_LEN || &map[..8] != MAGIC {
+            return Err(anyhow!("{path:?} is not a completions index"));
+        }
+        let version = read_u32(&map, 8)?;
+        if version != VERSION {
+            return Err(anyhow!(
+                "{path:?} is a version {version} index, but version {VERSION} is required; rebuild the index"
+            ));
+        }
+        let count = read_u32(&map, 12)? as usize;
+        if map.len() < HEADER_LEN + count * ENTRY_LEN {
+            return Err(anyhow!("{path:?} is truncated"));
+        }
+        trace!(path = as_debug!(path), count = count; "index opened");
+        Ok(Self { map, count })
+    }
+
+    /// The number of commands in the index.
+    pub(crate) fn len(&self) -> usize {
+        self.count
+    }
+
+    /// The size of the index, in bytes.
+    pub(crate) fn size(&self) -> usize {
+        self.map.len()
+    }
+
+    /// The slice of the file given by the offset and length at `at`.
+    fn slice(&self, at: usize) -> Result<&[u8]> {
+        let offset = read_u32(&self.map, at)? as usize;
+        let len = read_u32(&self.map, at + 4)? as usize;
+        self.map
+            .get(offset..offset + len)
+            .ok_or_else(|| anyhow!("index entry at {at} is out of bounds"))
+    }
+
+    /// The name of the `n`th command.
+    fn name(&self, n: usize) -> Result<&str> {
+        Ok(std::str::from_utf8(
+            self.slice(HEADER_LEN + n * ENTRY_LEN)?,
+        )?)
+    }
+
+    /// Every command in the index, in order.
+    pub(crate) fn commands(&self) -> impl Iterator<Item = Result<&str>> {
+        (0..self.count).map(|n| self.name(n))
+    }
+
+    /// Whether the index contains completions for `command`.
+    pub(crate) fn contains(&self, command: &str) -> Result<bool> {
+        Ok(self.find(command)?.is_some())
+    }
+
+    /// Every command in the index which starts with `prefix`, in order.
+    pub(crate) fn commands_starting_with(&self, prefix: &str) -> Result<Vec<&str>> {
+        let mut commands = vec![];
+        for n in self.lower_bound(prefix)?..self.count {
+            let name = self.name(n)?;
+            if !name.starts_with(prefix) {
+                break;
+            }
+            commands.push(name);
+        }
+        Ok(commands)
+    }
+
+    /// Binary search the table for `command`.
+    fn find(&self, command: &str) -> Result<Option<usize>> {
+        let n = self.lower_bound(command)?;
+        Ok((n < self.count && self.name(n)? == command).then_some(n))
+    }
+
+    /// Binary search the table for the first command which doesn't sort
+    /// before `command`.
+    fn lower_bound(&self, command: &str) -> Result<usize> {
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.name(mid)? < command {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        Ok(low)
+    }
+
+    /// The completions for `command`, if there are any.
+    pub(crate) fn get(&self, command: &str) -> Result<Option<Vec<CompletionLine>>> {
+        let Some(n) = self.find(command)? else {
+            return Ok(None);
+        };
+        let data = self.slice(HEADER_LEN + n * ENTRY_LEN + 8)?;
+        Ok(Some(bincode::deserialize(data)?))
+    }
+}
+
+/// Print the indexed completions for the command in `opts` as JSON, or
+/// `null` if there are none.
+pub(crate) fn lookup(opts: &LookupOptions) -> Result<()> {
+    let index = IndexFile::open(&opts.index.index)?;
+    println!("{}", serde_json::to_string(&index.get(&opts.command)?)?);
+    Ok(())
+}
+
+/// Print statistics about the index in `opts`.
+pub(crate) fn print_stats(opts: &IndexOptions) -> Result<()> {
+    let index = IndexFile::open(&opts.index)?;
+    let mut completions = 0;
+    for command in index.commands() {
+        completions += index.get(command?)?.map_or(0, |it| it.len());
+    }
+    println!(
+        "index version={VERSION} commands={} completions={completions} bytes={} location={:?}",
+        index.len(),
+        index.size(),
+        opts.index
+    );
+    Ok(())
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Result<u32> {
+    let bytes = bytes
+        .get(at..at + 4)
+        .ok_or_else(|| anyhow!("index is truncated"))?;
+    Ok(u32::from_le_bytes(bytes.try_into()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let mut index = Index::new();
+        for command in ["zzz", "mockery", "mockery-build"] {
+            index.insert(
+                command.into(),
+                vec![CompletionLine {
+                    long: vec![format!("{command}-flag")],
+                    command: Some(command.into()),
+                    condition: Some("__fish_use_subcommand".into()),
+                    ..Default::default()
+                }],
+            );
+        }
+        let dir = tempdir()?;
+        let path = dir.path().join("index.bin");
+        write_index(&index, &path)?;
+        let file = IndexFile::open(&path)?;
+        assert_eq!(file.len(), 3);
+        assert_eq!(
+            file.commands().collect::<Result<Vec<_>>>()?,
+            ["mockery", "mockery-build", "zzz"]
+        );
+        for (command, completions) in &index {
+            let found = file.get(command)?.expect("command to be found");
+            assert_eq!(found[0].long, completions[0].long);
+            assert_eq!(found[0].condition, completions[0].condition);
+        }
+        assert!(file.get("missing")?.is_none());
+        assert!(!file.contains("mock")?);
+        assert_eq!(file.commands_starting_with("mockery-")?, ["mockery-build"]);
+        assert_eq!(
+            file.commands_starting_with("mock")?,
+            ["mockery", "mockery-build"]
+        );
+        assert!(file.commands_starting_with("mockery ")?.is_empty());
+        assert!(file.commands_starting_with("zzzz")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_other_versions() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("index.bin");
+        write_index(&Index::new(), &path)?;
+        let mut bytes = fs::read(&path)?;
+        bytes[8] = bytes[8].wrapping_add(1);
+        fs::write(&path, bytes)?;
+        assert!(IndexFile::open(&path).is_err());
+        Ok(())
+    }
+}
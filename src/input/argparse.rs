@@ -0,0 +1,906 @@
+//! Generate completions from Python scripts which use argparse, by scanning
+//! their calls to it rather than running them.
+//!
+//! The parsers made with `ArgumentParser(...)` are followed through the
+//! variables they're assigned to, along with the subparsers, groups and
+//! parsers made from them (with `add_subparsers`, `add_argument_group`,
+//! `add_mutually_exclusive_group` and `add_parser`), so that each call to
+//! `add_argument` can be attributed to its command. Literal `choices` (lists,
+//! tuples, dictionaries, `range`s, and variables holding them) become the
+//! values to complete, and `type`s like `int` or `pathlib.Path` type the value.
+//! Anything which is only known while the script runs is left out.
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::{anyhow, Result};
+use log::debug;
+
+use super::{first_sentence, parse_option_spec, USE_SUBCOMMAND};
+use crate::{completion_line::CompletionLine, completions::Completions};
+
+/// The command a script defines, which is the name of the file without its
+/// extension, or of its package for a `__main__.py` (which is run with
+/// `python -m`).
+pub(crate) fn command_name(path: &Path) -> Option<String> {
+    let name = path.file_stem()?.to_str()?;
+    let name = if name == "__main__" {
+        path.parent()?.file_name()?.to_str()?
+    } else {
+        name
+    };
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Parse the completions defined by the script at `path`.
+pub(crate) fn parse_file(path: &Path) -> Result<Completions> {
+    let command = command_name(path).ok_or_else(|| anyhow!("{path:?} has no file name"))?;
+    parse(&command, &String::from_utf8_lossy(&fs::read(path)?))
+}
+
+/// Parse the completions defined by `script`, which is run as `command`
+/// unless its parser names the program.
+pub(crate) fn parse(command: &str, script: &str) -> Result<Completions> {
+    let mut scanner = Scanner::default();
+    if script.contains("ArgumentParser") {
+        for statement in statements(&tokenize(script)) {
+            scanner.statement(statement);
+        }
+    } else {
+        debug!(command = command; "script doesn't use argparse");
+    }
+    let completions = Completions::default();
+    let parents: Vec<_> = scanner
+        .parsers
+        .iter()
+        .flat_map(|it| it.parents.clone())
+        .collect();
+    let roots = scanner
+        .parsers
+        .iter()
+        .enumerate()
+        .filter(|(i, it)| it.root && !parents.contains(i));
+    for (n, (i, parser)) in roots.enumerate() {
+        match (&parser.prog, n) {
+            (Some(prog), _) => scanner.add(&completions, i, prog)?,
+            (None, 0) => scanner.add(&completions, i, command)?,
+            (None, _) => debug!(command = command; "skipping parser without a name"),
+        }
+    }
+    Ok(completions)
+}
+
+/// A token of Python source.
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Name(String),
+    Str(String),
+    Number(String),
+    Op(String),
+    Open(char),
+    Close(char),
+    /// The end of a statement
+    End,
+}
+
+/// Split `script` into tokens, leaving out comments and the newlines within
+/// brackets.
+fn tokenize(script: &str) -> Vec<Token> {
+    let chars: Vec<char> = script.chars().collect();
+    let mut tokens = vec![];
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '#' => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            '\\' if chars.get(i + 1) == Some(&'\n') => i += 1,
+            '\n' | ';' if depth == 0 => tokens.push(Token::End),
+            c if c.is_whitespace() => (),
+            '(' | '[' | '{' => {
+                depth += 1;
+                tokens.push(Token::Open(c));
+            }
+            ')' | ']' | '}' => {
+                depth = depth.saturating_sub(1);
+                tokens.push(Token::Close(c));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                let name: String = chars[start..i].iter().collect();
+                let is_prefix = name.len() <= 2 && name.chars().all(|c| "rRbBuUfF".contains(c));
+                if is_prefix && matches!(chars.get(i), Some('"' | '\'')) {
+                    let (string, end) = string(&chars, i, name.contains(['r', 'R']));
+                    tokens.push(Token::Str(string));
+                    i = end;
+                } else {
+                    tokens.push(Token::Name(name));
+                }
+                continue;
+            }
+            '"' | '\'' => {
+                let (string, end) = string(&chars, i, false);
+                tokens.push(Token::Str(string));
+                i = end;
+                continue;
+            }
+            c if c.is_ascii_digit() => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || "._".contains(chars[i])) {
+                    i += 1;
+                }
+                tokens.push(Token::Number(chars[start..i].iter().collect()));
+                continue;
+            }
+            _ => {
+                let two: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+                let is_op = (two.ends_with('=') && "=!<>:+-*/%&|^".contains(c))
+                    || ["**", "//", "->", "<<", ">>"].contains(&two.as_str());
+                let op = if two.chars().count() == 2 && is_op {
+                    two
+                } else {
+                    c.to_string()
+                };
+                i += op.chars().count();
+                tokens.push(Token::Op(op));
+                continue;
+            }
+        }
+        i += 1;
+    }
+    tokens.push(Token::End);
+    tokens
+}
+
+/// The string starting with the quote at `start`, and the index after it.
+fn string(chars: &[char], start: usize, raw: bool) -> (String, usize) {
+    let quote = chars[start];
+    let triple = chars.get(start + 1) == Some(&quote) && chars.get(start + 2) == Some(&quote);
+    let mut i = start + if triple { 3 } else { 1 };
+    let mut string = String::new();
+    while i < chars.len() {
+        let c = chars[i];
+        if c == quote
+            && (!triple || (chars.get(i + 1) == Some(&quote) && chars.get(i + 2) == Some(&quote)))
+        {
+            return (string, i + if triple { 3 } else { 1 });
+        }
+        if c == '\n' && !triple {
+            break;
+        }
+        if c == '\\' && let Some(&next) = chars.get(i + 1) {
+            i += 2;
+            match next {
+                _ if raw => {
+                    string.push('\\');
+                    string.push(next);
+                }
+                'n' => string.push('\n'),
+                't' => string.push('\t'),
+                '\n' => (),
+                '\\' | '\'' | '"' => string.push(next),
+                _ => {
+                    string.push('\\');
+                    string.push(next);
+                }
+            }
+            continue;
+        }
+        string.push(c);
+        i += 1;
+    }
+    (string, i)
+}
+
+/// The simple statements in `tokens`, including those after the `:` of a
+/// compound statement, like the body of `if x: y()`.
+fn statements(tokens: &[Token]) -> impl Iterator<Item = &[Token]> {
+    const COMPOUND: &[&str] = &[
+        "if", "elif", "else", "for", "while", "with", "try", "except", "finally", "def", "class",
+        "async",
+    ];
+    tokens
+        .split(|it| *it == Token::End)
+        .filter_map(|statement| match statement.first() {
+            Some(Token::Name(keyword)) if COMPOUND.contains(&keyword.as_str()) => {
+                let mut depth = 0;
+                let colon = statement.iter().position(|it| {
+                    match it {
+                        Token::Open(_) => depth += 1,
+                        Token::Close(_) => depth -= 1,
+                        _ => (),
+                    }
+                    depth == 0 && *it == Token::Op(":".into())
+                })?;
+                Some(&statement[colon + 1..])
+            }
+            Some(Token::Op(op)) if op == "@" => None,
+            Some(_) => Some(statement),
+            None => None,
+        })
+}
+
+/// A Python expression, as far as it matters.
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Str(String),
+    Number(String),
+    Name(String),
+    /// A list, tuple or set, or the keys of a dictionary
+    List(Vec<Expr>),
+    Attribute(Box<Expr>, String),
+    Call {
+        callee: Box<Expr>,
+        arguments: Vec<Expr>,
+        keywords: Vec<(String, Expr)>,
+    },
+    /// Anything else
+    Other,
+}
+
+/// Reads an expression from tokens.
+struct Reader<'a> {
+    tokens: &'a [Token],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+
+    /// Skip to the end of the current expression: the next `,`, `:` or `=`,
+    /// or closing bracket, outside of any brackets.
+    fn skip(&mut self) {
+        let mut depth = 0;
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Open(_) => depth += 1,
+                Token::Close(_) if depth == 0 => return,
+                Token::Close(_) => depth -= 1,
+                Token::Op(op) if depth == 0 && [",", ":", "="].contains(&op.as_str()) => return,
+                _ => (),
+            }
+            self.position += 1;
+        }
+    }
+
+    fn expr(&mut self) -> Expr {
+        let mut expr = self.primary();
+        loop {
+            match self.peek() {
+                Some(Token::Op(op)) if op == "." => {
+                    self.position += 1;
+                    let Some(Token::Name(name)) = self.next() else {
+                        self.skip();
+                        return Expr::Other;
+                    };
+                    expr = Expr::Attribute(Box::new(expr), name.clone());
+                }
+                Some(Token::Open('(')) => {
+                    self.position += 1;
+                    let (arguments, keywords) = self.arguments();
+                    expr = Expr::Call {
+                        callee: Box::new(expr),
+                        arguments,
+                        keywords,
+                    };
+                }
+                Some(Token::Open('[')) => {
+                    self.position += 1;
+                    self.skip_to_close();
+                    expr = Expr::Other;
+                }
+                Some(Token::Op(op)) if ![",", ":", "="].contains(&op.as_str()) => {
+                    // An operator, like in `"%s" % x`, whose result isn't
+                    // known. A string is kept as it's probably help text.
+                    self.skip();
+                    if !matches!(expr, Expr::Str(_)) {
+                        expr = Expr::Other;
+                    }
+                }
+                Some(Token::Name(_) | Token::Str(_) | Token::Number(_)) => {
+                    // A keyword, like in `a if b else c` or `x for x in y`.
+                    self.skip();
+                    expr = Expr::Other;
+                }
+                _ => return expr,
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Expr {
+        match self.next() {
+            Some(Token::Str(string)) => {
+                let mut string = string.clone();
+                while let Some(Token::Str(next)) = self.peek() {
+                    string.push_str(next);
+                    self.position += 1;
+                }
+                Expr::Str(string)
+            }
+            Some(Token::Number(number)) => Expr::Number(number.clone()),
+            Some(Token::Name(name)) if name == "lambda" || name == "not" => {
+                self.skip();
+                Expr::Other
+            }
+            Some(Token::Name(name)) => Expr::Name(name.clone()),
+            Some(Token::Open(bracket)) => {
+                let mut items = vec![];
+                let mut tuple = *bracket != '(';
+                loop {
+                    match self.peek() {
+                        Some(Token::Close(_)) | None => break,
+                        Some(Token::Op(op)) if op == "," => {
+                            tuple = true;
+                            self.position += 1;
+                        }
+                        Some(Token::Op(op)) if op == ":" => {
+                            // The value of a dictionary entry.
+                            self.position += 1;
+                            self.expr();
+                        }
+                        Some(Token::Name(name)) if name == "for" => {
+                            self.skip_to_close();
+                            return Expr::Other;
+                        }
+                        _ => items.push(self.expr()),
+                    }
+                }
+                self.position += 1;
+                if !tuple && items.len() == 1 {
+                    items.remove(0)
+                } else {
+                    Expr::List(items)
+                }
+            }
+            _ => {
+                self.skip();
+                Expr::Other
+            }
+        }
+    }
+
+    /// The arguments of a call, after its `(`.
+    fn arguments(&mut self) -> (Vec<Expr>, Vec<(String, Expr)>) {
+        let (mut arguments, mut keywords) = (vec![], vec![]);
+        loop {
+            match (self.peek(), self.tokens.get(self.position + 1)) {
+                (Some(Token::Close(_)) | None, _) => {
+                    self.position += 1;
+                    return (arguments, keywords);
+                }
+                (Some(Token::Op(op)), _) if op == "," => self.position += 1,
+                (Some(Token::Op(op)), _) if op == "*" || op == "**" => {
+                    self.position += 1;
+                    self.expr();
+                }
+                (Some(Token::Name(name)), Some(Token::Op(op))) if op == "=" => {
+                    self.position += 2;
+                    keywords.push((name.clone(), self.expr()));
+                }
+                _ => {
+                    let start = self.position;
+                    arguments.push(self.expr());
+                    if self.position == start {
+                        self.position += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Skip past the bracket closing the one just opened.
+    fn skip_to_close(&mut self) {
+        let mut depth = 0;
+        while let Some(token) = self.next() {
+            match token {
+                Token::Open(_) => depth += 1,
+                Token::Close(_) if depth == 0 => return,
+                Token::Close(_) => depth -= 1,
+                _ => (),
+            }
+        }
+    }
+}
+
+/// What a variable holds.
+#[derive(Debug, Clone)]
+enum Binding {
+    Parser(usize),
+    /// The subparsers of a parser
+    Subparsers(usize),
+    /// A group of the arguments of a parser, which is mutually exclusive if
+    /// it has an index
+    Group(usize, Option<usize>),
+    Value(Expr),
+}
+
+/// An argument added to a parser.
+#[derive(Debug, Default)]
+struct Argument {
+    names: Vec<String>,
+    help: Option<String>,
+    takes_value: bool,
+    /// Whether `--no-<name>` is accepted too
+    negatable: bool,
+    choices: Vec<String>,
+    value_type: Option<String>,
+    /// The mutually exclusive group it's in
+    group: Option<usize>,
+}
+
+/// A subcommand added to a parser.
+#[derive(Debug)]
+struct Subcommand {
+    names: Vec<String>,
+    help: Option<String>,
+    parser: usize,
+}
+
+#[derive(Debug, Default)]
+struct ParserInfo {
+    /// Whether this was made with `ArgumentParser`, rather than `add_parser`
+    root: bool,
+    prog: Option<String>,
+    parents: Vec<usize>,
+    arguments: Vec<Argument>,
+    subcommands: Vec<Subcommand>,
+}
+
+#[derive(Debug, Default)]
+struct Scanner {
+    variables: HashMap<String, Binding>,
+    parsers: Vec<ParserInfo>,
+    groups: usize,
+}
+
+impl Scanner {
+    fn statement(&mut self, tokens: &[Token]) {
+        let tokens = match tokens.first() {
+            Some(Token::Name(keyword)) if keyword == "return" || keyword == "yield" => &tokens[1..],
+            _ => tokens,
+        };
+        let mut depth = 0;
+        let assignment = tokens
+            .iter()
+            .enumerate()
+            .filter(|(_, it)| {
+                match it {
+                    Token::Open(_) => depth += 1,
+                    Token::Close(_) => depth -= 1,
+                    _ => (),
+                }
+                depth == 0 && **it == Token::Op("=".into())
+            })
+            .last()
+            .map(|(i, _)| i);
+        let (target, value) = match assignment {
+            Some(i) => (Some(&tokens[..i]), &tokens[i + 1..]),
+            None => (None, tokens),
+        };
+        let expr = Reader {
+            tokens: value,
+            position: 0,
+        }
+        .expr();
+        let binding = self.eval(&expr);
+        // Only a single name (like `parser` or `self.parser`) is bound.
+        let target = target.and_then(|target| {
+            let end = target
+                .iter()
+                .position(|it| *it == Token::Op(":".into()))
+                .unwrap_or(target.len());
+            target[..end]
+                .iter()
+                .map(|it| match it {
+                    Token::Name(name) => Some(name.as_str()),
+                    Token::Op(op) if op == "." => Some("."),
+                    _ => None,
+                })
+                .collect::<Option<String>>()
+        });
+        if let Some(target) = target {
+            match binding {
+                Some(binding) => self.variables.insert(target, binding),
+                None => self.variables.remove(&target),
+            };
+        }
+    }
+
+    /// Evaluate `expr`, adding what it adds to its parser, and return what it
+    /// evaluates to if that's known.
+    fn eval(&mut self, expr: &Expr) -> Option<Binding> {
+        match expr {
+            Expr::Name(_) | Expr::Attribute(..) => self.variables.get(&dotted(expr)?).cloned(),
+            Expr::Str(_) | Expr::Number(_) | Expr::List(_) => Some(Binding::Value(expr.clone())),
+            Expr::Call {
+                callee,
+                arguments,
+                keywords,
+            } => self.call(callee, arguments, keywords),
+            Expr::Other => None,
+        }
+    }
+
+    fn call(
+        &mut self,
+        callee: &Expr,
+        arguments: &[Expr],
+        keywords: &[(String, Expr)],
+    ) -> Option<Binding> {
+        let keyword = |name: &str| keywords.iter().find(|(it, _)| it == name).map(|(_, it)| it);
+        let parents = |scanner: &mut Self| match keyword("parents") {
+            Some(Expr::List(parents)) => parents
+                .iter()
+                .filter_map(|it| match scanner.eval(it) {
+                    Some(Binding::Parser(parser)) => Some(parser),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
+        };
+        if dotted(callee).map_or(false, |it| it.rsplit('.').next() == Some("ArgumentParser")) {
+            let parser = ParserInfo {
+                root: true,
+                prog: keyword("prog").and_then(|it| self.string(it)),
+                parents: parents(self),
+                ..ParserInfo::default()
+            };
+            self.parsers.push(parser);
+            return Some(Binding::Parser(self.parsers.len() - 1));
+        }
+        let Expr::Attribute(receiver, method) = callee else {
+            return None;
+        };
+        let (parser, group) = match self.eval(receiver)? {
+            Binding::Subparsers(parent) if method == "add_parser" => {
+                let name = arguments.first().and_then(|it| self.string(it))?;
+                let aliases = keyword("aliases").map(|it| self.strings(it));
+                let help = keyword("help")
+                    .or_else(|| keyword("description"))
+                    .and_then(|it| self.string(it));
+                let parser = ParserInfo {
+                    parents: parents(self),
+                    ..ParserInfo::default()
+                };
+                self.parsers.push(parser);
+                let parser = self.parsers.len() - 1;
+                self.parsers[parent].subcommands.push(Subcommand {
+                    names: [name]
+                        .into_iter()
+                        .chain(aliases.into_iter().flatten())
+                        .collect(),
+                    help,
+                    parser,
+                });
+                return Some(Binding::Parser(parser));
+            }
+            Binding::Parser(parser) => (parser, None),
+            Binding::Group(parser, group) => (parser, group),
+            _ => return None,
+        };
+        match method.as_str() {
+            "add_argument" => {
+                let argument = self.argument(arguments, keywords, group)?;
+                self.parsers[parser].arguments.push(argument);
+                None
+            }
+            "add_subparsers" => Some(Binding::Subparsers(parser)),
+            "add_argument_group" => Some(Binding::Group(parser, group)),
+            "add_mutually_exclusive_group" => {
+                self.groups += 1;
+                Some(Binding::Group(parser, Some(self.groups)))
+            }
+            _ => None,
+        }
+    }
+
+    /// The argument added by `add_argument`, unless its help is suppressed.
+    fn argument(
+        &mut self,
+        arguments: &[Expr],
+        keywords: &[(String, Expr)],
+        group: Option<usize>,
+    ) -> Option<Argument> {
+        let mut argument = Argument {
+            names: arguments.iter().filter_map(|it| self.string(it)).collect(),
+            takes_value: true,
+            group,
+            ..Argument::default()
+        };
+        for (keyword, value) in keywords {
+            match keyword.as_str() {
+                "help" if dotted(value).map_or(false, |it| it.ends_with("SUPPRESS")) => {
+                    return None
+                }
+                "help" => argument.help = self.string(value),
+                "action" => {
+                    let action = self
+                        .string(value)
+                        .or_else(|| dotted(value))
+                        .unwrap_or_default();
+                    let action = action.rsplit('.').next().unwrap_or_default();
+                    argument.negatable = action == "BooleanOptionalAction";
+                    argument.takes_value &= ![
+                        "store_true",
+                        "store_false",
+                        "store_const",
+                        "append_const",
+                        "count",
+                        "help",
+                        "version",
+                        "BooleanOptionalAction",
+                    ]
+                    .contains(&action);
+                }
+                "nargs" => argument.takes_value &= *value != Expr::Number("0".to_string()),
+                "choices" => argument.choices = self.strings(value),
+                "type" => argument.value_type = value_type(value).map(ToString::to_string),
+                _ => (),
+            }
+        }
+        (!argument.names.is_empty()).then_some(argument)
+    }
+
+    /// The value of `expr`, if it's a string.
+    fn string(&mut self, expr: &Expr) -> Option<String> {
+        match self.eval(expr)? {
+            Binding::Value(Expr::Str(it) | Expr::Number(it)) => Some(it),
+            _ => None,
+        }
+    }
+
+    /// The values listed by `expr`, if it's a literal collection (or a
+    /// variable holding one), the keys of a dictionary or a `range`.
+    fn strings(&mut self, expr: &Expr) -> Vec<String> {
+        match expr {
+            Expr::Call {
+                callee, arguments, ..
+            } => match (callee.as_ref(), arguments.as_slice()) {
+                (Expr::Name(function), [it])
+                    if ["list", "tuple", "sorted", "set"].contains(&function.as_str()) =>
+                {
+                    self.strings(it)
+                }
+                (Expr::Attribute(receiver, method), []) if method == "keys" => {
+                    self.strings(receiver)
+                }
+                (Expr::Name(function), bounds) if function == "range" => {
+                    let bounds: Option<Vec<i64>> = bounds
+                        .iter()
+                        .map(|it| match it {
+                            Expr::Number(it) => it.parse().ok(),
+                            _ => None,
+                        })
+                        .collect();
+                    match bounds.as_deref() {
+                        Some([end]) if *end <= 100 => (0..*end).map(|it| it.to_string()).collect(),
+                        Some([start, end]) if end - start <= 100 => {
+                            (*start..*end).map(|it| it.to_string()).collect()
+                        }
+                        _ => vec![],
+                    }
+                }
+                _ => vec![],
+            },
+            Expr::List(items) => items.iter().filter_map(|it| self.string(it)).collect(),
+            Expr::Name(_) | Expr::Attribute(..) => match self.eval(expr) {
+                Some(Binding::Value(value)) if value != *expr => self.strings(&value),
+                _ => vec![],
+            },
+            _ => vec![],
+        }
+    }
+
+    /// Add the completions for `parser`, which is `command`, and its
+    /// subcommands.
+    fn add(&self, completions: &Completions, parser: usize, command: &str) -> Result<()> {
+        let arguments = self.arguments(parser);
+        for argument in &arguments {
+            let (options, positionals): (Vec<_>, Vec<_>) =
+                argument.names.iter().partition(|it| it.starts_with('-'));
+            if positionals.is_empty() {
+                let spec = options
+                    .iter()
+                    .map(|it| it.as_str())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let Some(mut option) = parse_option_spec(&spec) else {
+                    continue;
+                };
+                option.command = Some(command.to_string());
+                option.description = argument.help.as_deref().and_then(first_sentence);
+                option.require_parameter = argument.takes_value;
+                if argument.takes_value {
+                    option.value_type = argument.value_type.clone();
+                    if !argument.choices.is_empty() {
+                        option.argument = Some(shell_words::join(&argument.choices));
+                        option.no_files = true;
+                    }
+                }
+                if let Some(group) = argument.group {
+                    option.exclusions = arguments
+                        .iter()
+                        .filter(|it| it.group == Some(group) && it.names != argument.names)
+                        .filter_map(|it| it.names.iter().find(|it| it.starts_with('-')))
+                        .cloned()
+                        .collect();
+                }
+                let negation =
+                    (argument.negatable && !option.long.is_empty()).then(|| CompletionLine {
+                        long: vec![format!("no-{}", option.long[0])],
+                        command: Some(command.to_string()),
+                        ..CompletionLine::default()
+                    });
+                completions.add(option)?;
+                if let Some(negation) = negation {
+                    completions.add(negation)?;
+                }
+            } else {
+                for choice in &argument.choices {
+                    completions.add(CompletionLine {
+                        command: Some(command.to_string()),
+                        argument: Some(shell_words::quote(choice).into_owned()),
+                        description: argument.help.as_deref().and_then(first_sentence),
+                        no_files: true,
+                        ..CompletionLine::default()
+                    })?;
+                }
+            }
+        }
+        for subcommand in &self.parsers[parser].subcommands {
+            for name in &subcommand.names {
+                completions.add(CompletionLine {
+                    command: Some(command.to_string()),
+                    argument: Some(shell_words::quote(name).into_owned()),
+                    description: subcommand.help.as_deref().and_then(first_sentence),
+                    condition: Some(USE_SUBCOMMAND.to_string()),
+                    no_files: true,
+                    ..CompletionLine::default()
+                })?;
+                self.add(completions, subcommand.parser, &format!("{command} {name}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The arguments of `parser`, including those of its parents.
+    fn arguments(&self, parser: usize) -> Vec<&Argument> {
+        let parser = &self.parsers[parser];
+        parser
+            .parents
+            .iter()
+            .flat_map(|it| self.arguments(*it))
+            .chain(&parser.arguments)
+            .collect()
+    }
+}
+
+/// The dotted name of `expr`, like `argparse.ArgumentParser`.
+fn dotted(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Name(name) => Some(name.clone()),
+        Expr::Attribute(receiver, name) => Some(format!("{}.{name}", dotted(receiver)?)),
+        _ => None,
+    }
+}
+
+/// The nu type of the values converted by the `type` of an argument, if it's
+/// more specific than a string.
+fn value_type(expr: &Expr) -> Option<&'static str> {
+    let function = match expr {
+        Expr::Call { callee, .. } => dotted(callee)?,
+        _ => dotted(expr)?,
+    };
+    match function.rsplit('.').next()? {
+        "int" => Some("int"),
+        "float" => Some("float"),
+        "Path" | "PurePath" | "FileType" | "open" => Some("path"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = r#"
+import argparse
+from pathlib import Path
+
+FORMATS = ["json", "yaml"]
+
+
+def main():
+    common = argparse.ArgumentParser(add_help=False)
+    common.add_argument("-q", "--quiet", action="store_true", help="Be quiet. Really.")
+
+    parser = argparse.ArgumentParser(description=__doc__, parents=[common])
+    parser.add_argument("--format", choices=FORMATS, help="The output "
+                        "format (default: %(default)s)")
+    parser.add_argument("-o", "--out", type=Path, help="Where to write it")
+    parser.add_argument("--level", type=int, choices=range(1, 4))
+    parser.add_argument("--secret", help=argparse.SUPPRESS)
+    colour = parser.add_mutually_exclusive_group()
+    colour.add_argument("--color", action=argparse.BooleanOptionalAction)
+    colour.add_argument("--plain", action="store_true")
+    parser.add_argument("mode", choices={"fast": 1, "slow": 2}.keys(), nargs="?")
+
+    subparsers = parser.add_subparsers(dest="command")
+    build = subparsers.add_parser("build", aliases=["b"], help="Build it")
+    build.add_argument("--jobs", "-j", type=int)
+    if True: subparsers.add_parser("clean", help="Clean up").add_argument("--all")
+    return parser.parse_args()
+"#;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let completions = parse("mockery", SCRIPT)?;
+        let completions = completions.read().expect("rwlock read access");
+        let root = &completions[String::from("mockery")];
+        let flags: Vec<_> = root
+            .iter()
+            .filter_map(|it| it.long.first().map(String::as_str))
+            .collect();
+        assert_eq!(
+            flags,
+            vec!["quiet", "format", "out", "level", "color", "no-color", "plain"]
+        );
+        assert!(!root[0].takes_value());
+        assert_eq!(root[0].description.as_deref(), Some("Be quiet"));
+        assert_eq!(root[1].values(), vec!["json", "yaml"]);
+        assert_eq!(
+            root[1].description.as_deref(),
+            Some("The output format (default: %(default)s)")
+        );
+        assert_eq!(root[2].value_type.as_deref(), Some("path"));
+        assert_eq!(root[3].value_type.as_deref(), Some("int"));
+        assert_eq!(root[3].values(), vec!["1", "2", "3"]);
+        assert_eq!(root[4].exclusions, vec!["--plain"]);
+        assert_eq!(root[6].exclusions, vec!["--color"]);
+        assert_eq!(root[7].argument.as_deref(), Some("fast"));
+        assert_eq!(root[8].argument.as_deref(), Some("slow"));
+        let subcommands: Vec<_> = root
+            .iter()
+            .filter(|it| it.condition.as_deref() == Some(USE_SUBCOMMAND))
+            .map(|it| it.argument.as_deref().unwrap())
+            .collect();
+        assert_eq!(subcommands, vec!["build", "b", "clean"]);
+        for name in ["mockery build", "mockery b"] {
+            let build = &completions[String::from(name)];
+            assert_eq!(build[0].long, vec!["jobs"]);
+            assert_eq!(build[0].short, vec!["j"]);
+        }
+        assert_eq!(
+            completions[String::from("mockery clean")][0].long,
+            vec!["all"]
+        );
+        assert!(parse("mockery", "print('hi')")?.read().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_name() {
+        assert_eq!(
+            command_name(Path::new("tools/deploy.py")).as_deref(),
+            Some("deploy")
+        );
+        assert_eq!(
+            command_name(Path::new("mockery/__main__.py")).as_deref(),
+            Some("mockery")
+        );
+    }
+}
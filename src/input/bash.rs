@@ -0,0 +1,471 @@
+//! Generate completions from bash completion scripts, like those in
+//! `/usr/share/bash-completion/completions`.
+//!
+//! Bash completions are usually computed while completing, so only the
+//! literal lists of words are extracted:
+//!
+//!  - `complete -W 'words' command`
+//!  - in the functions registered with `complete -F function command`, the
+//!    words listed by `compgen -W` or assigned to `COMPREPLY`, directly or
+//!    through a variable which is assigned a literal string or array
+//!
+//! Words starting with a dash are options, and any others are the values of
+//! a positional argument. Words listed in a branch of a `case` on the
+//! previous word (`$prev`) are the values of the options it matches, and those
+//! in a branch of a `case` on the first word (`${COMP_WORDS[1]}`) are for a
+//! subcommand. Lists which can't be extracted are reported, since they'll be
+//! missing from the generated definitions.
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+use anyhow::Result;
+use lazy_regex::{regex_captures, regex_replace_all};
+use log::{debug, trace, warn};
+
+use super::{
+    parse_option_spec,
+    shell::{lex, walk, Frame, Scope, Statement, Token},
+};
+use crate::{completion_line::CompletionLine, completions::Completions};
+
+/// The command a completion script is named after (e.g. `foo` for
+/// `foo.bash` or `foo-completion.bash`), which is usually the command it
+/// completes.
+pub(crate) fn command_name(path: &Path) -> Option<String> {
+    let mut name = path.file_name()?.to_str()?;
+    for suffix in [".bash-completion", ".bash", ".sh", "-completion"] {
+        name = name.strip_suffix(suffix).unwrap_or(name);
+    }
+    let name = name.strip_prefix('_').unwrap_or(name);
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Parse the completions registered by the script at `path`.
+pub(crate) fn parse_file(path: &Path) -> Result<Completions> {
+    parse(&String::from_utf8_lossy(&fs::read(path)?))
+}
+
+/// Parse the completions registered with `complete` by `script`.
+pub(crate) fn parse(script: &str) -> Result<Completions> {
+    let mut parser = Parser::default();
+    walk(script, |scope, statement| {
+        parser.statement(scope, statement);
+        Ok(())
+    })?;
+    parser.completions()
+}
+
+/// What completes the commands registered with `complete`.
+#[derive(Clone)]
+enum Completer {
+    /// The words given with `-W`
+    Words(Vec<String>),
+    /// The function given with `-F`
+    Function(String),
+}
+
+/// A list of words found in a function.
+struct List {
+    /// The function the words were found in
+    function: String,
+    /// The subcommand the words are for, if they're in a branch for one
+    subcommand: Option<String>,
+    /// The options the words are the values of, if they're in a branch for
+    /// the previous word
+    options: Option<String>,
+    words: Vec<String>,
+}
+
+/// What's been parsed so far.
+#[derive(Default)]
+struct Parser {
+    /// The value of each variable which has been assigned a string
+    variables: HashMap<String, String>,
+    /// The functions which have been defined
+    functions: HashSet<String>,
+    /// The lists of words found in functions
+    lists: Vec<List>,
+    /// What completes each command, in the order they were registered
+    registrations: Vec<(String, Completer)>,
+}
+
+/// Whether the words in `text` can't be known without running the script.
+fn is_dynamic(text: &str) -> bool {
+    text.contains(['$', '`', '(', '*', '?'])
+}
+
+impl Parser {
+    fn statement(&mut self, scope: &Scope, statement: Statement) {
+        let function = scope.frames.iter().rev().find_map(|frame| match frame {
+            Frame::Function { name, .. } => Some(name.as_str()),
+            _ => None,
+        });
+        if let Some(function) = function {
+            self.functions.insert(function.to_string());
+        }
+        match statement {
+            Statement::Command(words) => {
+                if words[0][0] == "complete" {
+                    self.complete(scope, &words[1..]);
+                    return;
+                }
+                self.assignments(words);
+                if let Some(function) = function {
+                    for word in words {
+                        self.substitutions(scope, function, &word[0]);
+                    }
+                }
+            }
+            Statement::Array { name, elements } => {
+                let Some(function) = function else {
+                    return;
+                };
+                for element in elements {
+                    self.substitutions(scope, function, &element[0]);
+                }
+                if name != "COMPREPLY" {
+                    return;
+                }
+                let words: Vec<_> = elements.iter().flatten().cloned().collect();
+                if words.iter().any(|word| word.contains("compgen")) {
+                    // handled as a substitution
+                } else if words.iter().any(|word| is_dynamic(word)) {
+                    warn!(function = function, words = words.join(" "); "COMPREPLY is too dynamic to translate");
+                } else {
+                    self.list(scope, function, words);
+                }
+            }
+        }
+    }
+
+    /// Remember the strings assigned to variables by the command made up of
+    /// `words`, like `local opts="--all --verbose"`.
+    fn assignments(&mut self, words: &[Vec<String>]) {
+        let declaration = ["local", "declare", "typeset", "readonly", "export"];
+        let words = match words.first() {
+            Some(first) if declaration.contains(&first[0].as_str()) => &words[1..],
+            _ => words,
+        };
+        for word in words {
+            if let Some((_, name, value)) = regex_captures!(r"^(\w+)=(.*)$"s, &word[0]) {
+                self.variables.insert(name.to_string(), value.to_string());
+            } else if !word[0].starts_with('-') {
+                break;
+            }
+        }
+    }
+
+    /// The words in `text`, with the variables and arrays in it replaced by
+    /// their values, or `None` if any of it is too dynamic to translate.
+    fn expand(&self, scope: &Scope, text: &str) -> Option<Vec<String>> {
+        let known = Cell::new(true);
+        let text = regex_replace_all!(r"\$\{?(\w+)(?:\[[@*]\])?\}?", text, |_, name: &str| {
+            if let Some(value) = self.variables.get(name) {
+                value.clone()
+            } else if let Some(elements) = scope.arrays.get(name) {
+                elements
+                    .iter()
+                    .flatten()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            } else {
+                known.set(false);
+                String::new()
+            }
+        });
+        (known.get() && !is_dynamic(&text))
+            .then(|| text.split_whitespace().map(str::to_string).collect())
+    }
+
+    /// Find the lists of words given to `compgen -W` in the command
+    /// substitutions in `word`, like `$(compgen -W "a b" -- "$cur")`.
+    fn substitutions(&mut self, scope: &Scope, function: &str, word: &str) {
+        let mut rest = word;
+        while let Some(start) = rest.find("compgen") {
+            let command = &rest[start..];
+            // The substitution ends at the first unmatched parenthesis.
+            let mut depth = 0;
+            let end = command
+                .char_indices()
+                .find(|&(_, c)| {
+                    match c {
+                        '(' => depth += 1,
+                        ')' => depth -= 1,
+                        _ => {}
+                    }
+                    depth < 0 || c == '`'
+                })
+                .map_or(command.len(), |(i, _)| i);
+            rest = &command[end..];
+            let words: Vec<_> = lex(&command[..end])
+                .into_iter()
+                .filter_map(|token| match token {
+                    Token::Word(word) => Some(word.concat()),
+                    _ => None,
+                })
+                .collect();
+            let Some(list) = words
+                .iter()
+                .position(|word| word == "-W")
+                .and_then(|i| words.get(i + 1))
+            else {
+                trace!(function = function, command = &command[..end]; "compgen without a word list");
+                continue;
+            };
+            match self.expand(scope, list) {
+                Some(words) => self.list(scope, function, words),
+                None => {
+                    warn!(function = function, words = list; "compgen word list is too dynamic to translate")
+                }
+            }
+        }
+    }
+
+    /// Add a list of words found in `function`.
+    fn list(&mut self, scope: &Scope, function: &str, words: Vec<String>) {
+        let mut subcommand = None;
+        let mut options = None;
+        for frame in &scope.frames {
+            let Frame::Case { subject, pattern: Some(pattern) } = frame else {
+                continue;
+            };
+            if subject.contains("prev") || subject.contains("COMP_CWORD-1") {
+                options = Some(pattern.replace('|', " ")).filter(|it| it.starts_with('-'));
+            } else if subject.contains("COMP_WORDS[1]") || subject.contains("words[1]") {
+                let name = pattern.split('|').next().unwrap_or(pattern);
+                subcommand = (!is_dynamic(name)).then(|| name.to_string());
+            }
+        }
+        self.lists.push(List {
+            function: function.to_string(),
+            subcommand,
+            options,
+            words,
+        });
+    }
+
+    /// Remember what completes the commands registered by `complete` with the
+    /// arguments `args`.
+    fn complete(&mut self, scope: &Scope, args: &[Vec<String>]) {
+        let mut completer = None;
+        let mut commands = vec![];
+        let mut args = args.iter().map(|it| it[0].as_str());
+        while let Some(arg) = args.next() {
+            match arg {
+                "-F" => completer = args.next().map(|it| Completer::Function(it.to_string())),
+                "-W" => {
+                    let Some(words) = args.next() else { continue };
+                    match self.expand(scope, words) {
+                        Some(words) => completer = Some(Completer::Words(words)),
+                        None => {
+                            warn!(words = words; "complete word list is too dynamic to translate")
+                        }
+                    }
+                }
+                "-C" => {
+                    let command = args.next().unwrap_or_default();
+                    warn!(command = command; "completions from a command are too dynamic to translate");
+                }
+                "-o" | "-A" | "-G" | "-X" | "-P" | "-S" => {
+                    args.next();
+                }
+                _ if arg.starts_with('-') => {}
+                _ => commands.push(arg.to_string()),
+            }
+        }
+        let Some(completer) = completer else {
+            debug!(commands = commands.join(" "); "nothing to translate for commands");
+            return;
+        };
+        for command in commands {
+            self.registrations.push((command, completer.clone()));
+        }
+    }
+
+    /// The completions for every registered command.
+    fn completions(self) -> Result<Completions> {
+        // The options and positional arguments of each (sub)command
+        let mut lines: BTreeMap<String, Vec<CompletionLine>> = BTreeMap::new();
+        for (command, completer) in &self.registrations {
+            match completer {
+                Completer::Words(words) => {
+                    add_words(lines.entry(command.clone()).or_default(), words)
+                }
+                Completer::Function(function) => {
+                    if !self.functions.contains(function) {
+                        warn!(command = command, function = function; "completion function isn't defined in the same script, so can't be translated");
+                        continue;
+                    }
+                    let lists = self.lists.iter().filter(|it| it.function == *function);
+                    // Add the options before their values.
+                    let (values, words): (Vec<_>, Vec<_>) =
+                        lists.partition(|list| list.options.is_some());
+                    for list in words.into_iter().chain(values) {
+                        let key = match &list.subcommand {
+                            Some(subcommand) => format!("{command} {subcommand}"),
+                            None => command.clone(),
+                        };
+                        let lines = lines.entry(key).or_default();
+                        match &list.options {
+                            Some(options) => add_values(lines, options, &list.words),
+                            None => add_words(lines, &list.words),
+                        }
+                    }
+                }
+            }
+        }
+        let completions = Completions::default();
+        for (command, lines) in lines {
+            for mut line in lines {
+                line.command = Some(command.clone());
+                completions.add(line)?;
+            }
+        }
+        Ok(completions)
+    }
+}
+
+/// Add the options in `words` to `lines`, and the rest as the values of a
+/// positional argument.
+fn add_words(lines: &mut Vec<CompletionLine>, words: &[String]) {
+    let mut values = vec![];
+    for word in words {
+        if !word.starts_with('-') {
+            values.push(word.as_str());
+        } else if let Some(option) = parse_option_spec(word)
+            && !lines.iter().any(|it| it.overlaps(&option))
+        {
+            lines.push(option);
+        }
+    }
+    if !values.is_empty() {
+        lines.push(CompletionLine {
+            argument: Some(shell_words::join(values)),
+            ..Default::default()
+        });
+    }
+}
+
+/// Add `values` as the values taken by each of `options` (like `-o
+/// --output`). Those of the options which haven't been listed are taken to
+/// be synonyms of the first which has.
+fn add_values(lines: &mut Vec<CompletionLine>, options: &str, values: &[String]) {
+    let mut matched = vec![];
+    let mut unmatched = vec![];
+    for name in options.split_whitespace() {
+        let Some(parsed) = parse_option_spec(name) else {
+            continue;
+        };
+        match lines.iter().position(|it| it.overlaps(&parsed)) {
+            Some(index) => matched.push(index),
+            None => unmatched.push(parsed),
+        }
+    }
+    if !unmatched.is_empty() {
+        let index = match matched.first() {
+            Some(&index) => index,
+            None => {
+                lines.push(CompletionLine::default());
+                matched.push(lines.len() - 1);
+                lines.len() - 1
+            }
+        };
+        let option = &mut lines[index];
+        for parsed in unmatched {
+            option.short.extend(parsed.short);
+            option.long.extend(parsed.long);
+            option.old_option.extend(parsed.old_option);
+        }
+    }
+    for index in matched {
+        let option = &mut lines[index];
+        let mut all = option.values();
+        for value in values {
+            if !value.starts_with('-') && !all.contains(value) {
+                all.push(value.clone());
+            }
+        }
+        option.argument = Some(shell_words::join(all));
+        option.require_parameter = true;
+        option.no_files = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = r#"
+_mockery() {
+    local cur prev opts
+    cur="${COMP_WORDS[COMP_CWORD]}"
+    prev="${COMP_WORDS[COMP_CWORD-1]}"
+    opts="--help --verbose --color"
+
+    case "$prev" in
+        --color|-c)
+            COMPREPLY=( $(compgen -W "auto always never" -- "$cur") )
+            return 0
+            ;;
+        --user)
+            COMPREPLY=( $(compgen -W "$(cut -d: -f1 /etc/passwd)" -- "$cur") )
+            return 0
+            ;;
+    esac
+    case "${COMP_WORDS[1]}" in
+        build)
+            COMPREPLY=( --release --target )
+            return 0
+            ;;
+    esac
+    if [[ $cur == -* ]]; then
+        COMPREPLY=( $(compgen -W "${opts} --user" -- "$cur") )
+    else
+        COMPREPLY=( $(compgen -W "build run" -- "$cur") )
+    fi
+}
+complete -o default -F _mockery mockery
+complete -W 'start stop --now' mockeryctl
+complete -F _longopt other
+"#;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let completions = parse(SCRIPT)?;
+        let completions = completions.read().expect("rwlock read access");
+        let options = &completions[String::from("mockery")];
+        assert_eq!(options.len(), 5);
+        assert_eq!(options[0].long, vec!["help"]);
+        assert_eq!(options[2].long, vec!["color"]);
+        assert_eq!(options[2].short, vec!["c"]);
+        assert_eq!(options[2].values(), vec!["auto", "always", "never"]);
+        assert_eq!(options[3].long, vec!["user"]);
+        assert!(!options[3].takes_value());
+        assert_eq!(options[4].values(), vec!["build", "run"]);
+        let build = &completions[String::from("mockery build")];
+        assert_eq!(build[0].long, vec!["release"]);
+        assert_eq!(build[1].long, vec!["target"]);
+        let ctl = &completions[String::from("mockeryctl")];
+        assert_eq!(ctl[0].long, vec!["now"]);
+        assert_eq!(ctl[1].values(), vec!["start", "stop"]);
+        assert!(!completions.contains_key("other"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_name() {
+        assert_eq!(
+            command_name(Path::new("/usr/share/bash-completion/completions/git")).as_deref(),
+            Some("git")
+        );
+        assert_eq!(
+            command_name(Path::new("mockery-completion.bash")).as_deref(),
+            Some("mockery")
+        );
+    }
+}
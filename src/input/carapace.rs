@@ -0,0 +1,352 @@
+//! Generate completions from carapace specs (<https://carapace.sh>), YAML
+//! files describing a command's flags, subcommands and the values they
+//! complete.
+//!
+//! Flags are written like `-v, --verbose`, followed by `=` when they take a
+//! value, `?` when they take an optional one or `&` when they're hidden. The
+//! values listed under `completion` are kept as the values to complete, with
+//! their descriptions (written like `value\tdescription`). Values listed by
+//! macros (like `$(git branch)`) are computed while completing, so they can't
+//! be translated, except that `$files` and `$directories` (like
+//! `$files([.yaml])`) type the value of a flag, or the positional arguments,
+//! as a path.
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Result};
+use log::{debug, warn};
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+
+use super::{parse_option_spec, USE_SUBCOMMAND};
+use crate::{completion_line::CompletionLine, completions::Completions};
+
+/// A command (or subcommand) described by a spec.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Command {
+    name: String,
+    aliases: Vec<String>,
+    description: Option<String>,
+    hidden: bool,
+    /// The flags of this command, keyed by their spec
+    flags: Mapping,
+    /// The flags of this command which its subcommands take too
+    #[serde(rename = "persistentflags")]
+    persistent_flags: Mapping,
+    /// Groups of the names of flags which can't be given together
+    #[serde(rename = "exclusiveflags")]
+    exclusive_flags: Vec<Vec<String>>,
+    completion: Completion,
+    commands: Vec<Command>,
+}
+
+/// The values completed by a command.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Completion {
+    /// The values of each flag, keyed by its name
+    flag: Mapping,
+    /// The values of each positional argument, in order
+    positional: Vec<Vec<String>>,
+    /// The values of any further positional arguments
+    #[serde(rename = "positionalany")]
+    positional_any: Vec<String>,
+}
+
+/// The command a spec describes, which is the name of the file without its
+/// extension (e.g. `mytool` for `mytool.yaml`).
+pub(crate) fn command_name(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    let name = name
+        .strip_suffix(".yaml")
+        .or_else(|| name.strip_suffix(".yml"))
+        .unwrap_or(name);
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Parse the completions defined by the spec at `path`.
+pub(crate) fn parse_file(path: &Path) -> Result<Completions> {
+    parse(&fs::read_to_string(path)?)
+        .map_err(|err| anyhow!("parsing carapace spec {path:?}: {err}"))
+}
+
+/// Parse the completions defined by the spec `yaml`.
+pub(crate) fn parse(yaml: &str) -> Result<Completions> {
+    let command: Command = serde_yaml::from_str(yaml)?;
+    if command.name.is_empty() {
+        return Err(anyhow!("the spec doesn't name its command"));
+    }
+    let completions = Completions::default();
+    add_command(&completions, &command.name, &command, &[])?;
+    Ok(completions)
+}
+
+/// Add the completions for `spec`, the command `name`, which also takes the
+/// `inherited` flags of the commands it's a subcommand of.
+fn add_command(
+    completions: &Completions,
+    name: &str,
+    spec: &Command,
+    inherited: &[CompletionLine],
+) -> Result<()> {
+    let parse = |flags: &Mapping| -> Vec<CompletionLine> {
+        flags
+            .iter()
+            .filter_map(|(flag, description)| {
+                let description = match description {
+                    Value::String(it) => Some(it.as_str()),
+                    Value::Mapping(it) => it.get("description").and_then(Value::as_str),
+                    _ => None,
+                };
+                option(name, flag.as_str()?, description, &spec.completion.flag)
+            })
+            .collect()
+    };
+    let mut options = parse(&spec.flags);
+    let local = options.len();
+    options.extend(parse(&spec.persistent_flags));
+    for group in &spec.exclusive_flags {
+        let names: Vec<_> = group
+            .iter()
+            .filter_map(|name| find(&options, name).map(|it| typed(&options[it])))
+            .collect();
+        for name in group {
+            if let Some(it) = find(&options, name) {
+                let own = typed(&options[it]);
+                let exclusions = &mut options[it].exclusions;
+                exclusions.extend(names.iter().filter(|it| **it != own).cloned());
+            }
+        }
+    }
+    let inherited: Vec<_> = inherited
+        .iter()
+        .filter(|it| !options.iter().any(|option| option.overlaps(it)))
+        .map(|it| CompletionLine {
+            command: Some(name.to_string()),
+            ..it.clone()
+        })
+        .collect();
+    // The persistent flags of this command and those it inherited.
+    let persistent: Vec<_> = options[local..].iter().chain(&inherited).cloned().collect();
+    for option in options.into_iter().chain(inherited) {
+        completions.add(option)?;
+    }
+    let positional = spec
+        .completion
+        .positional
+        .iter()
+        .flatten()
+        .chain(&spec.completion.positional_any);
+    for value in positional {
+        if let Some(value_type) = macro_type(value) {
+            completions.add(CompletionLine {
+                command: Some(name.to_string()),
+                value_type: Some(value_type.to_string()),
+                force_files: true,
+                ..CompletionLine::default()
+            })?;
+        } else if let Some((value, description)) = literal(name, value) {
+            completions.add(CompletionLine {
+                command: Some(name.to_string()),
+                argument: Some(shell_words::quote(&value).into_owned()),
+                description,
+                no_files: true,
+                ..CompletionLine::default()
+            })?;
+        }
+    }
+    for subcommand in &spec.commands {
+        let names = [&subcommand.name].into_iter().chain(&subcommand.aliases);
+        for subcommand_name in names {
+            if !subcommand.hidden {
+                completions.add(CompletionLine {
+                    command: Some(name.to_string()),
+                    argument: Some(shell_words::quote(subcommand_name).into_owned()),
+                    description: subcommand.description.clone(),
+                    condition: Some(USE_SUBCOMMAND.to_string()),
+                    no_files: true,
+                    ..CompletionLine::default()
+                })?;
+            }
+            add_command(
+                completions,
+                &format!("{name} {subcommand_name}"),
+                subcommand,
+                &persistent,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// The spec of a flag without the modifiers at the end of it.
+fn flag_names(flag: &str) -> &str {
+    flag.trim_end_matches(['=', '?', '*', '&', '!'])
+}
+
+/// The option of `command` defined by the spec `flag`, unless it's hidden.
+/// The values of each flag are listed in `values`.
+fn option(
+    command: &str,
+    flag: &str,
+    description: Option<&str>,
+    values: &Mapping,
+) -> Option<CompletionLine> {
+    let modifiers = &flag[flag_names(flag).len()..];
+    if modifiers.contains('&') {
+        return None;
+    }
+    let Some(mut option) = parse_option_spec(flag_names(flag)) else {
+        debug!(command = command, flag = flag; "no options in flag spec");
+        return None;
+    };
+    option.command = Some(command.to_string());
+    option.description = description.map(ToString::to_string);
+    option.require_parameter = modifiers.contains(['=', '?']);
+    if !option.require_parameter {
+        return Some(option);
+    }
+    let key = option
+        .long
+        .first()
+        .or_else(|| option.old_option.first())
+        .or_else(|| option.short.first())?;
+    let values: Vec<String> = values
+        .get(key.as_str())
+        .and_then(|it| serde_yaml::from_value(it.clone()).ok())
+        .unwrap_or_default();
+    option.value_type = values
+        .iter()
+        .find_map(|it| macro_type(it))
+        .map(ToString::to_string);
+    let values: Vec<_> = values
+        .iter()
+        .filter_map(|value| literal(command, value))
+        .map(|(value, _)| value)
+        .collect();
+    if !values.is_empty() {
+        option.argument = Some(shell_words::join(values));
+        option.no_files = true;
+    }
+    Some(option)
+}
+
+/// The nu type of the values listed by the macro `value`, if it lists paths,
+/// like `path` for `$files` or `$files([.yaml])`.
+fn macro_type(value: &str) -> Option<&'static str> {
+    let name = value.strip_prefix('$')?;
+    match name.split('(').next()? {
+        "files" => Some("path"),
+        "directories" => Some("directory"),
+        _ => None,
+    }
+}
+
+/// The value listed by `value`, like `value\tdescription`, unless it's a
+/// macro which is computed while completing.
+fn literal(command: &str, value: &str) -> Option<(String, Option<String>)> {
+    if value.starts_with('$') {
+        if macro_type(value).is_none() {
+            warn!(command = command, value = value; "value is computed by a macro while completing, which isn't translated");
+        }
+        return None;
+    }
+    let mut parts = value.split('\t');
+    let value = parts.next()?.to_string();
+    let description = parts
+        .next()
+        .filter(|it| !it.is_empty())
+        .map(ToString::to_string);
+    Some((value, description))
+}
+
+/// The index of the option named `name` (without its dashes) in `options`.
+fn find(options: &[CompletionLine], name: &str) -> Option<usize> {
+    options.iter().position(|it| {
+        it.long
+            .iter()
+            .chain(&it.short)
+            .chain(&it.old_option)
+            .any(|it| it == name)
+    })
+}
+
+/// The name of `option` as it's typed, like `--verbose`.
+fn typed(option: &CompletionLine) -> String {
+    match (
+        option.long.first(),
+        option.old_option.first(),
+        option.short.first(),
+    ) {
+        (Some(long), _, _) => format!("--{long}"),
+        (_, Some(old), _) => format!("-{old}"),
+        (_, _, short) => format!("-{}", short.map_or("", String::as_str)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: &str = r#"
+name: mockery
+description: A mock tool
+flags:
+  -v, --verbose: Be verbose
+  --json: Output JSON
+  --yaml: Output YAML
+  --secret&: Hidden
+persistentflags:
+  -c, --config=: The config file
+exclusiveflags:
+  - [json, yaml]
+completion:
+  flag:
+    config: ["$files([.yaml, .yml])"]
+  positional:
+    - ["one\tThe first", two]
+  positionalany: ["$directories"]
+commands:
+  - name: build
+    aliases: [b]
+    description: Build it
+    flags:
+      --format=: Output format
+      --branch=: The branch
+    completion:
+      flag:
+        format: [json, "yaml\tYAML output"]
+        branch: ["$(git branch)"]
+"#;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let completions = parse(SPEC)?;
+        let completions = completions.read().expect("rwlock read access");
+        let root = &completions[String::from("mockery")];
+        assert_eq!(root[0].long, vec!["verbose"]);
+        assert_eq!(root[1].exclusions, vec!["--yaml"]);
+        assert_eq!(root[2].exclusions, vec!["--json"]);
+        assert_eq!(root[3].long, vec!["config"]);
+        assert!(root[3].takes_value());
+        assert_eq!(root[3].value_type.as_deref(), Some("path"));
+        assert_eq!(root[4].argument.as_deref(), Some("one"));
+        assert_eq!(root[4].description.as_deref(), Some("The first"));
+        assert_eq!(root[6].argument, None);
+        assert_eq!(root[6].value_type.as_deref(), Some("directory"));
+        assert_eq!(root[7].argument.as_deref(), Some("build"));
+        assert_eq!(root[7].condition.as_deref(), Some(USE_SUBCOMMAND));
+        assert_eq!(root[8].argument.as_deref(), Some("b"));
+        for name in ["mockery build", "mockery b"] {
+            let build = &completions[String::from(name)];
+            assert_eq!(build.len(), 3);
+            assert_eq!(build[0].values(), vec!["json", "yaml"]);
+            assert!(build[1].takes_value());
+            assert_eq!(build[1].argument, None);
+            assert_eq!(build[2].long, vec!["config"]);
+            assert_eq!(build[2].value_type.as_deref(), Some("path"));
+        }
+        assert!(parse("flags: {}").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,438 @@
+//! Parse fish completion scripts. Most are just a list of `complete`
+//! commands, but those generated by clap and cobra need more care:
+//!
+//!  - clap defines helper functions (`__fish_<prog>_needs_command` and
+//!    `__fish_<prog>_using_subcommand`) for the conditions of its
+//!    completions, which are translated into the subcommands the completions
+//!    are for. Its lists of values with descriptions (like
+//!    `{json\t'JSON',yaml\t''}`) are translated too.
+//!  - cobra's completions are computed by the command itself while
+//!    completing (with `<prog> __complete`), so with [`Config::query_cobra`]
+//!    the command is asked for its subcommands and flags instead.
+//!
+//! Scripts which define functions (like those in `~/.config/fish/functions`)
+//! are read for the commands their functions and aliases wrap (like
+//! `function ll --wraps ls`), along with their `complete` commands.
+use std::{fs, path::Path, time::Duration};
+
+use anyhow::Result;
+use lazy_regex::{regex, regex_captures, regex_is_match};
+use log::{as_debug, trace, warn};
+
+use super::{
+    help::{self, Help},
+    parse_option_spec, USE_SUBCOMMAND,
+};
+use crate::{
+    completion_line::CompletionLine,
+    completions::{split_shell_words, Completions},
+    config::Config,
+};
+
+/// A program which generates fish completion scripts.
+#[derive(Debug, PartialEq, Eq)]
+enum Generator {
+    /// clap, and the name of the program in its helper functions
+    Clap(String),
+    /// cobra, and the command the script completes
+    Cobra(String),
+}
+
+/// Which program generated `script`, if any.
+fn generator(script: &str) -> Option<Generator> {
+    for line in script.lines() {
+        if let Some((_, prog)) = regex_captures!(
+            r"^function __fish_(\S+)_(?:needs_command|using_subcommand)\b",
+            line
+        ) {
+            return Some(Generator::Clap(prog.to_string()));
+        }
+        if regex_is_match!(r"^function __\S+_perform_completion\b", line) {
+            let (_, command) = script
+                .lines()
+                .find_map(|line| regex_captures!(r"^complete -c (\S+)", line))?;
+            return Some(Generator::Cobra(command.to_string()));
+        }
+    }
+    None
+}
+
+/// Parse the completions defined by the fish script at `path`.
+pub(crate) fn parse_file(path: &Path) -> Result<Completions> {
+    let script = String::from_utf8_lossy(&fs::read(path)?).into_owned();
+    trace!(file = as_debug!(path); "read file for processing");
+    match generator(&script) {
+        Some(Generator::Clap(prog)) => parse_clap(&script, &prog),
+        Some(Generator::Cobra(command)) if Config::query_cobra() => {
+            query_cobra(&command, Config::help_depth(), Config::help_timeout())
+        }
+        Some(Generator::Cobra(command)) => {
+            warn!(command = command; "completions generated by cobra are computed while completing, pass --query-cobra to ask the command for them");
+            Ok(Completions::default())
+        }
+        None if regex_is_match!(r"(?m)^\s*(?:function|alias)\s", &script) => {
+            parse_functions(&script)
+        }
+        None => Completions::parse(script.lines()),
+    }
+}
+
+/// Parse a script which defines functions, keeping the commands they wrap
+/// (see [`wrapper`]) and any `complete` commands. Everything else in it is
+/// left out.
+fn parse_functions(script: &str) -> Result<Completions> {
+    let completions = Completions::default();
+    for line in script.lines().map(str::trim_start) {
+        if line.starts_with("complete ") {
+            completions.clone().parse_one_completion(line)?;
+        } else if let Some((command, wraps)) = wrapper(line) && !wraps.is_empty() {
+            trace!(command = command, wraps = as_debug!(wraps); "found wrapper");
+            completions.add(CompletionLine {
+                command: Some(command),
+                wraps,
+                ..CompletionLine::default()
+            })?;
+        }
+    }
+    Ok(completions)
+}
+
+/// The function defined by `line`, if it's a `function` or `alias` command,
+/// and the commands it wraps. An alias wraps the command it runs.
+fn wrapper(line: &str) -> Option<(String, Vec<String>)> {
+    let mut words = split_shell_words(line).ok()?;
+    // Only the first command on the line is the definition.
+    if let Some(end) = words.iter().position(|it| it.ends_with(';')) {
+        words.truncate(end + 1);
+        words[end].pop();
+    }
+    let (keyword, words) = words.split_first()?;
+    match keyword.as_str() {
+        "function" => {
+            let (name, mut options) = words.split_first()?;
+            let mut wraps = vec![];
+            while let Some((option, rest)) = options.split_first() {
+                options = rest;
+                if let Some(wrapped) = option.strip_prefix("--wraps=") {
+                    wraps.push(wrapped.to_string());
+                } else if option == "--wraps" || option == "-w" {
+                    wraps.extend(options.first().cloned());
+                    options = options.get(1..).unwrap_or_default();
+                } else if let Some(wrapped) = option.strip_prefix("-w") && !option.starts_with("--") {
+                    wraps.push(wrapped.to_string());
+                }
+            }
+            Some((name.clone(), wraps))
+        }
+        "alias" => match words
+            .iter()
+            .filter(|it| !it.starts_with('-'))
+            .collect::<Vec<_>>()
+            .as_slice()
+        {
+            [definition] => {
+                let (name, body) = definition.split_once('=')?;
+                Some((name.to_string(), vec![body.to_string()]))
+            }
+            [name, body @ ..] if !body.is_empty() => Some((
+                name.to_string(),
+                vec![body
+                    .iter()
+                    .map(|it| it.as_str())
+                    .collect::<Vec<_>>()
+                    .join(" ")],
+            )),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Parse a script generated by clap, whose helper functions are named after
+/// `prog`.
+fn parse_clap(script: &str, prog: &str) -> Result<Completions> {
+    // The helper functions aren't completions.
+    let parsed = Completions::parse(script.lines().filter(|line| line.starts_with("complete ")))?;
+    let completions = Completions::default();
+    for (_, lines) in parsed.read().expect("rwlock read access").iter() {
+        for line in lines {
+            for line in translate_clap(line.clone(), prog) {
+                completions.add(line)?;
+            }
+        }
+    }
+    Ok(completions)
+}
+
+/// Translate the conditions of a completion generated by clap into the
+/// subcommand it's for, and its values with descriptions into separate
+/// completions (for a positional argument) or just the values (for an
+/// option).
+fn translate_clap(mut line: CompletionLine, prog: &str) -> Vec<CompletionLine> {
+    let needs_command = format!("__fish_{prog}_needs_command");
+    let using_subcommand = format!("__fish_{prog}_using_subcommand");
+    let mut subcommands = vec![];
+    let mut lists_subcommands = false;
+    let mut others = vec![];
+    let condition = line.condition.take().unwrap_or_default();
+    for clause in condition
+        .split("; and ")
+        .flat_map(|it| it.split(" && "))
+        .filter(|it| !it.trim().is_empty())
+    {
+        match clause.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [function] if *function == needs_command || *function == USE_SUBCOMMAND => {
+                lists_subcommands = true
+            }
+            // Any further names are aliases.
+            [function, subcommand, ..]
+                if *function == using_subcommand || *function == "__fish_seen_subcommand_from" =>
+            {
+                subcommands.push(*subcommand)
+            }
+            ["not", "__fish_seen_subcommand_from", ..] => lists_subcommands = true,
+            _ => others.push(clause.trim()),
+        }
+    }
+    if let Some(command) = &mut line.command {
+        for subcommand in subcommands {
+            command.push(' ');
+            command.push_str(subcommand);
+        }
+    }
+    let positional = line.short.is_empty() && line.long.is_empty() && line.old_option.is_empty();
+    // Clap only offers the options of a command until one of its subcommands
+    // is given, but they're options of that command all the same.
+    line.condition = if lists_subcommands && positional {
+        Some(USE_SUBCOMMAND.to_string())
+    } else if others.is_empty() {
+        None
+    } else {
+        Some(others.join("; and "))
+    };
+    let Some(choices) = line.argument.as_deref().and_then(choices) else {
+        return vec![line];
+    };
+    if positional {
+        choices
+            .into_iter()
+            .map(|(value, description)| CompletionLine {
+                argument: Some(shell_words::quote(&value).into_owned()),
+                description: description.or_else(|| line.description.clone()),
+                ..line.clone()
+            })
+            .collect()
+    } else {
+        line.argument = Some(shell_words::join(
+            choices.into_iter().map(|(value, _)| value),
+        ));
+        vec![line]
+    }
+}
+
+/// The values listed by clap along with their descriptions, like
+/// `{json\t'JSON',yaml\t''}` or `json\t'JSON' yaml\t''`.
+fn choices(argument: &str) -> Option<Vec<(String, Option<String>)>> {
+    let choices: Vec<_> = regex!(r"(?:^|[{,\s])([^{},\s]+)\\t'((?:[^'\\]|\\.)*)'")
+        .captures_iter(argument)
+        .map(|it| {
+            let description = it[2].replace("\\'", "'");
+            (
+                it[1].to_string(),
+                (!description.is_empty()).then_some(description),
+            )
+        })
+        .collect();
+    (!choices.is_empty()).then_some(choices)
+}
+
+/// The candidates printed by `<prog> __complete`, each with its
+/// description, leaving out the directive cobra prints at the end.
+fn cobra_candidates(output: &str) -> Vec<(String, Option<String>)> {
+    output
+        .lines()
+        .filter(|line| {
+            !line.is_empty() && !line.starts_with(':') && !line.starts_with("Completion ended")
+        })
+        .map(|line| match line.split_once('\t') {
+            Some((value, description)) => (value.to_string(), Some(description.to_string())),
+            None => (line.to_string(), None),
+        })
+        .collect()
+}
+
+/// Ask the cobra command at `executable` for the subcommands and flags of
+/// `subcommands`, each query taking at most `timeout`.
+fn cobra_help(executable: &Path, subcommands: &[String], timeout: Duration) -> Result<Help> {
+    let query = |word: &str| -> Result<_> {
+        let mut args = vec!["__complete".to_string()];
+        args.extend(subcommands.iter().cloned());
+        args.push(word.to_string());
+        Ok(cobra_candidates(&help::run(executable, &args, timeout)?))
+    };
+    let mut help = Help::default();
+    for (value, description) in query("")? {
+        if !value.starts_with('-') {
+            help.subcommands.push((value, description));
+        }
+    }
+    for (flag, description) in query("-")? {
+        let Some(mut option) = parse_option_spec(&flag) else {
+            continue;
+        };
+        // A flag's shorthand is listed separately, right after it.
+        if let Some(previous) = help.options.last_mut()
+            && previous.description == description
+            && previous.short.is_empty()
+            && option.long.is_empty()
+        {
+            previous.short.extend(option.short);
+        } else {
+            option.description = description;
+            help.options.push(option);
+        }
+    }
+    Ok(help)
+}
+
+/// Ask the cobra command `command` for its subcommands and flags, and those
+/// of its subcommands up to `depth` levels deep, each query taking at most
+/// `timeout`.
+fn query_cobra(command: &str, depth: usize, timeout: Duration) -> Result<Completions> {
+    let executable = which::which(command)?;
+    let completions = Completions::default();
+    let mut queue = vec![(vec![], 0)];
+    while let Some((subcommands, level)) = queue.pop() {
+        let help = match cobra_help(&executable, &subcommands, timeout) {
+            Ok(help) => help,
+            Err(err) if !subcommands.is_empty() => {
+                warn!(command = command, subcommands = as_debug!(subcommands), error = as_debug!(err); "failed to query subcommand");
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        if level < depth {
+            for (subcommand, _) in help.subcommands.iter().rev() {
+                if !["help", "completion"].contains(&subcommand.as_str()) {
+                    let mut subcommands = subcommands.clone();
+                    subcommands.push(subcommand.clone());
+                    queue.push((subcommands, level + 1));
+                }
+            }
+        }
+        let name = [command.to_string()]
+            .into_iter()
+            .chain(subcommands)
+            .collect::<Vec<_>>()
+            .join(" ");
+        help::add(&completions, &name, help)?;
+    }
+    Ok(completions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLAP: &str = r#"# Print an optspec for argparse to handle cmd's options that are independent of any subcommand.
+function __fish_mockery_global_optspecs
+	string join \n v/verbose h/help
+end
+
+function __fish_mockery_needs_command
+	set -l cmd (commandline -opc)
+	set -e cmd[1]
+	argparse -s (__fish_mockery_global_optspecs) -- $cmd 2>/dev/null
+	or return
+	if set -q argv[1]
+		echo $argv[1]
+		return 1
+	end
+	return 0
+end
+
+function __fish_mockery_using_subcommand
+	set -l cmd (__fish_mockery_needs_command)
+	test -z "$cmd"
+	and return 1
+	contains -- $cmd[1] $argv
+end
+
+complete -c mockery -n "__fish_mockery_needs_command" -s v -l verbose -d 'Be verbose'
+complete -c mockery -n "__fish_mockery_needs_command" -f -a "build" -d 'Build it'
+complete -c mockery -n "__fish_mockery_needs_command" -f -a "remote"
+complete -c mockery -n "__fish_mockery_using_subcommand build" -l format -d 'Output format' -r -f -a "{json\t'JSON output',yaml\t''}"
+complete -c mockery -n "__fish_mockery_using_subcommand build" -s o -l out -r -F
+complete -c mockery -n "__fish_mockery_using_subcommand remote; and not __fish_seen_subcommand_from add list" -f -a "add"
+complete -c mockery -n "__fish_mockery_using_subcommand remote; and __fish_seen_subcommand_from add" -f -a "ssh\t'' https\t'Over HTTPS'"
+"#;
+
+    #[test]
+    fn test_parse_clap() -> Result<()> {
+        assert_eq!(generator(CLAP), Some(Generator::Clap("mockery".into())));
+        let completions = parse_clap(CLAP, "mockery")?;
+        let completions = completions.read().expect("rwlock read access");
+        let root = &completions[String::from("mockery")];
+        assert_eq!(root.len(), 3);
+        assert_eq!(root[0].long, vec!["verbose"]);
+        assert_eq!(root[0].condition, None);
+        assert_eq!(root[1].argument.as_deref(), Some("build"));
+        assert_eq!(root[1].condition.as_deref(), Some(USE_SUBCOMMAND));
+        let build = &completions[String::from("mockery build")];
+        assert_eq!(build[0].values(), vec!["json", "yaml"]);
+        assert_eq!(build[0].condition, None);
+        assert_eq!(build[1].short, vec!["o"]);
+        assert!(build[1].force_files);
+        let remote = &completions[String::from("mockery remote")];
+        assert_eq!(remote[0].argument.as_deref(), Some("add"));
+        assert_eq!(remote[0].condition.as_deref(), Some(USE_SUBCOMMAND));
+        let add = &completions[String::from("mockery remote add")];
+        assert_eq!(add[0].argument.as_deref(), Some("ssh"));
+        assert_eq!(add[1].argument.as_deref(), Some("https"));
+        assert_eq!(add[1].description.as_deref(), Some("Over HTTPS"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cobra() {
+        let script = "# fish completion for mockery  -*- shell-script -*-\n\
+                      function __mockery_perform_completion\nend\n\
+                      complete -c mockery -e\n";
+        assert_eq!(generator(script), Some(Generator::Cobra("mockery".into())));
+        assert_eq!(
+            cobra_candidates("build\tBuild it\nhelp\n:4\nCompletion ended with directive: ShellCompDirectiveNoFileComp\n"),
+            vec![
+                ("build".to_string(), Some("Build it".to_string())),
+                ("help".to_string(), None)
+            ]
+        );
+        assert_eq!(generator("complete -c mockery -s v\n"), None);
+    }
+
+    #[test]
+    fn test_parse_functions() -> Result<()> {
+        let completions = parse_functions(
+            "function ll --wraps 'ls -l' --description 'List in long format'\n\
+             \tls -l $argv\n\
+             end\n\
+             function g -w git; git $argv; end\n\
+             function helper\n\
+             end\n\
+             alias la='ls -a'\n\
+             alias --save gs git status\n\
+             complete -c ll -s h -l human-readable\n",
+        )?;
+        let completions = completions.read().expect("rwlock read access");
+        assert_eq!(completions[String::from("ll")][0].wraps, vec!["ls -l"]);
+        assert_eq!(completions[String::from("ll")][1].short, vec!["h"]);
+        assert_eq!(completions[String::from("la")][0].wraps, vec!["ls -a"]);
+        assert_eq!(completions[String::from("g")][0].wraps, vec!["git"]);
+        assert_eq!(completions[String::from("gs")][0].wraps, vec!["git status"]);
+        assert!(completions[String::from("helper")].is_empty());
+        assert_eq!(
+            wrapper("function f --wraps=cat -w less"),
+            Some(("f".into(), vec!["cat".into(), "less".into()]))
+        );
+        Ok(())
+    }
+}
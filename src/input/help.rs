@@ -0,0 +1,443 @@
+//! Generate completions from the output of `<command> --help`, either by
+//! running the command or from help text captured in a file.
+//!
+//! The usual layouts of GNU/getopt, clap, cobra and argparse help are
+//! understood: each indented line starting with a dash lists some options,
+//! followed by their description after a gap of at least two spaces or on the
+//! following lines, and the entries in a "commands" section (or argparse's
+//! `{a,b,c}` choices) are subcommands.
+use std::{
+    fs,
+    io::{self, Read},
+    os::unix::{fs::PermissionsExt, process::CommandExt},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use log::{as_debug, debug, trace, warn};
+use tempfile::tempdir;
+
+use super::{first_sentence, parse_option_spec, USE_SUBCOMMAND};
+use crate::{completion_line::CompletionLine, completions::Completions};
+
+/// The most help text which is read from a command, so that a misbehaving
+/// command can't use up all the memory.
+const MAX_HELP_LEN: u64 = 1024 * 1024;
+
+/// The executable to run for `source`, if `source` is a command rather than
+/// a file of captured help text. Sources which don't exist are looked up in
+/// `$PATH`.
+pub(crate) fn executable(source: &Path) -> Option<PathBuf> {
+    match fs::metadata(source) {
+        Ok(metadata) if metadata.is_file() => {
+            (metadata.permissions().mode() & 0o111 != 0).then(|| source.to_path_buf())
+        }
+        Ok(_) => None,
+        Err(_) => which::which(source).ok(),
+    }
+}
+
+/// The command whose help the source at `path` contains (e.g. `cargo` for
+/// `cargo`, `/usr/bin/cargo` or `cargo.txt`).
+pub(crate) fn command_name(path: &Path) -> Option<String> {
+    let name = if executable(path).is_some() {
+        path.file_name()?
+    } else {
+        path.file_stem()?
+    };
+    name.to_str().map(ToString::to_string)
+}
+
+/// The options and subcommands listed in some help text.
+#[derive(Debug, Default)]
+pub(crate) struct Help {
+    pub(crate) options: Vec<CompletionLine>,
+    /// The name and description of each subcommand
+    pub(crate) subcommands: Vec<(String, Option<String>)>,
+}
+
+/// Parse the completions from the source at `path`: run it (and its
+/// subcommands, up to `depth` levels deep) with `--help`, each for at most
+/// `timeout`, or read the captured help text in it.
+pub(crate) fn parse_file(path: &Path, depth: usize, timeout: Duration) -> Result<Completions> {
+    let command =
+        command_name(path).ok_or_else(|| anyhow!("no command name for source {path:?}"))?;
+    let completions = Completions::default();
+    match executable(path) {
+        Some(executable) => {
+            let mut queue = vec![(vec![], 0)];
+            while let Some((subcommands, level)) = queue.pop() {
+                let text = match run_help(&executable, &subcommands, timeout) {
+                    Ok(text) => text,
+                    Err(err) if !subcommands.is_empty() => {
+                        warn!(command = command, subcommands = as_debug!(subcommands), error = as_debug!(err); "failed to get help for subcommand");
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                };
+                let name = [command.clone()]
+                    .into_iter()
+                    .chain(subcommands.iter().cloned())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let help = parse(&text);
+                if level < depth {
+                    for (subcommand, _) in help.subcommands.iter().rev() {
+                        // These print the help of other subcommands, or
+                        // scripts for other shells
+                        if !["help", "completion", "completions"].contains(&subcommand.as_str()) {
+                            let mut subcommands = subcommands.clone();
+                            subcommands.push(subcommand.clone());
+                            queue.push((subcommands, level + 1));
+                        }
+                    }
+                }
+                add(&completions, &name, help)?;
+            }
+        }
+        None => add(&completions, &command, parse(&fs::read_to_string(path)?))?,
+    }
+    Ok(completions)
+}
+
+/// Add the options and subcommands in `help` to the completions for
+/// `command`.
+pub(super) fn add(completions: &Completions, command: &str, help: Help) -> Result<()> {
+    for mut option in help.options {
+        option.command = Some(command.to_string());
+        completions.add(option)?;
+    }
+    for (subcommand, description) in help.subcommands {
+        completions.add(CompletionLine {
+            command: Some(command.to_string()),
+            condition: Some(USE_SUBCOMMAND.to_string()),
+            argument: Some(subcommand),
+            description,
+            no_files: true,
+            ..Default::default()
+        })?;
+    }
+    Ok(())
+}
+
+/// Run `executable` with `subcommands` and `--help` (see [`run`]).
+fn run_help(executable: &Path, subcommands: &[String], timeout: Duration) -> Result<String> {
+    let mut args = subcommands.to_vec();
+    args.push("--help".to_string());
+    run(executable, &args, timeout)
+}
+
+/// Run `executable` with `args` and return what it printed to stdout (or
+/// stderr, if nothing was printed to stdout).
+///
+/// The command is run in an empty temporary directory, which is also its
+/// `HOME`, with a minimal environment and no input, so that it's less likely
+/// to do anything but print its help. This isn't a sandbox though: the
+/// command can still read and write anything the user can. It's run in its
+/// own process group, which is killed once the command exits (taking anything
+/// it left running in the background with it), or if it takes longer than
+/// `timeout`.
+pub(super) fn run(executable: &Path, args: &[String], timeout: Duration) -> Result<String> {
+    let dir = tempdir()?;
+    debug!(executable = as_debug!(executable), args = as_debug!(args); "running command");
+    let mut child = Command::new(executable)
+        .args(args)
+        .current_dir(dir.path())
+        .process_group(0)
+        .env_clear()
+        .env("PATH", std::env::var_os("PATH").unwrap_or_default())
+        .env("HOME", dir.path())
+        .env("LANG", "C")
+        .env("TERM", "dumb")
+        .env("NO_COLOR", "1")
+        .env("PAGER", "cat")
+        .env("MANPAGER", "cat")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let read = |mut pipe: Box<dyn Read + Send>| {
+        thread::spawn(move || {
+            let mut output = vec![];
+            let _ = (&mut pipe).take(MAX_HELP_LEN).read_to_end(&mut output);
+            String::from_utf8_lossy(&output).into_owned()
+        })
+    };
+    let stdout = read(Box::new(child.stdout.take().expect("stdout was piped")));
+    let stderr = read(Box::new(child.stderr.take().expect("stderr was piped")));
+    let deadline = Instant::now() + timeout;
+    while !has_exited(&child)? {
+        if Instant::now() >= deadline {
+            kill_group(&mut child)?;
+            return Err(anyhow!(
+                "{executable:?} {args:?} took longer than {timeout:?}"
+            ));
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    // Anything the command left running could otherwise hold its output open,
+    // so that it's never finished being read. What was already printed can
+    // still be read once it's killed.
+    kill_group(&mut child)?;
+    let stdout = stdout.join().expect("reading stdout");
+    let stderr = stderr.join().expect("reading stderr");
+    Ok(if stdout.trim().is_empty() {
+        stderr
+    } else {
+        stdout
+    })
+}
+
+/// Whether `child` has exited, without reaping it, so that its process group
+/// can't be reused until it's killed (see [`kill_group`]).
+fn has_exited(child: &Child) -> io::Result<bool> {
+    // SAFETY: a zeroed siginfo_t is valid, and waitid only writes to it.
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let result = unsafe {
+        libc::waitid(
+            libc::P_PID,
+            child.id(),
+            &mut info,
+            libc::WEXITED | libc::WNOWAIT | libc::WNOHANG,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // The pid is left as zero if the child is still running.
+    Ok(unsafe { info.si_pid() } != 0)
+}
+
+/// Kill every process in the group led by `child`, which was spawned in its
+/// own process group, and reap `child`.
+fn kill_group(child: &mut Child) -> io::Result<()> {
+    // SAFETY: killpg only sends a signal. The group can't have been reused,
+    // as `child` hasn't been reaped yet.
+    if unsafe { libc::killpg(child.id() as libc::pid_t, libc::SIGKILL) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    child.wait()?;
+    Ok(())
+}
+
+/// The indentation of `line`.
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// Split an entry like `-v, --verbose   Use verbose output` into the part
+/// before the first gap of two or more spaces (or a tab) and the rest.
+fn split_entry(entry: &str) -> (&str, &str) {
+    let gap = [entry.find("  "), entry.find('\t')]
+        .into_iter()
+        .flatten()
+        .min();
+    match gap {
+        Some(gap) => (&entry[..gap], entry[gap..].trim()),
+        None => (entry, ""),
+    }
+}
+
+/// Whether `name` looks like the name of a subcommand.
+fn is_subcommand_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Parse the options and subcommands listed in `text`.
+pub(crate) fn parse(text: &str) -> Help {
+    let mut help = Help::default();
+    let mut in_commands = false;
+    // The indentation and description of the entry being parsed, and whether
+    // it's an option (or a subcommand)
+    let mut entry: Option<(usize, String, bool)> = None;
+    let finish = |help: &mut Help, entry: Option<(usize, String, bool)>| {
+        let Some((_, description, is_option)) = entry else {
+            return;
+        };
+        let description = first_sentence(&description);
+        if is_option {
+            if let Some(option) = help.options.last_mut() {
+                option.description = description;
+            }
+        } else if let Some((_, subcommand)) = help.subcommands.last_mut() {
+            *subcommand = description;
+        }
+    };
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        let indentation = indent(line);
+        if indentation == 0 && !trimmed.starts_with('-') {
+            finish(&mut help, entry.take());
+            // A section heading, like `Options:` or `Available Commands:`
+            let heading = trimmed.trim_end_matches(':').to_lowercase();
+            in_commands = heading.ends_with("commands") || heading.ends_with("subcommands");
+            trace!(heading = heading, commands = in_commands; "help section");
+            continue;
+        }
+        if let Some((entry_indentation, description, _)) = &mut entry
+            && indentation > *entry_indentation
+            && !trimmed.starts_with('-')
+        {
+            // A continuation of the entry's description
+            if !description.is_empty() {
+                description.push(' ');
+            }
+            description.push_str(trimmed);
+            continue;
+        }
+        finish(&mut help, entry.take());
+        let (spec, description) = split_entry(trimmed);
+        if spec.starts_with('-') {
+            if let Some(option) = parse_option_spec(spec) {
+                if help.options.iter().any(|it| it.overlaps(&option)) {
+                    trace!(spec = spec; "skipping option which was already listed");
+                    continue;
+                }
+                help.options.push(option);
+                entry = Some((indentation, description.to_string(), true));
+            }
+        } else if let Some(choices) = spec.strip_prefix('{').and_then(|it| it.strip_suffix('}')) {
+            // argparse lists its subcommands as choices, like `{build,run}`
+            for choice in choices.split(',').filter(|it| is_subcommand_name(it)) {
+                help.subcommands.push((choice.to_string(), None));
+            }
+            // and then lists them again with their descriptions
+            in_commands = true;
+        } else if in_commands {
+            let name = spec.split(',').next().unwrap_or(spec).trim();
+            if is_subcommand_name(name) {
+                if let Some(existing) = help.subcommands.iter().position(|(it, _)| it == name) {
+                    help.subcommands.remove(existing);
+                }
+                help.subcommands.push((name.to_string(), None));
+                entry = Some((indentation, description.to_string(), false));
+            }
+        }
+    }
+    finish(&mut help, entry);
+    help
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_kills_background_processes() -> Result<()> {
+        let dir = tempdir()?;
+        let script = dir.path().join("mockery");
+        fs::write(&script, "#!/bin/sh\necho 'Usage: mockery'\nsleep 30 &\n")?;
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755))?;
+        let started = Instant::now();
+        let output = run(&script, &[], Duration::from_secs(10))?;
+        assert_eq!(output.trim(), "Usage: mockery");
+        assert!(started.elapsed() < Duration::from_secs(10));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_gnu() {
+        let help = parse(
+            "Usage: ls [OPTION]... [FILE]...
+List information about the FILEs (the current directory by default).
+
+Mandatory arguments to long options are mandatory for short options too.
+  -a, --all                  do not ignore entries starting with .
+      --block-size=SIZE      with -l, scale sizes by SIZE when printing them;
+                             e.g., '--block-size=M'; see SIZE format below
+  -w, --width=COLS           set output width to COLS.  0 means no limit
+",
+        );
+        assert_eq!(help.options.len(), 3);
+        assert_eq!(help.options[0].long, vec!["all"]);
+        assert_eq!(
+            help.options[1].description.as_deref(),
+            Some("with -l, scale sizes by SIZE when printing them; e.g., '--block-size=M'; see SIZE format below")
+        );
+        assert!(help.options[2].takes_value());
+        assert!(help.subcommands.is_empty());
+    }
+
+    #[test]
+    fn test_parse_clap() {
+        let help = parse(
+            "Rust's package manager
+
+Usage: cargo [OPTIONS] [COMMAND]
+
+Options:
+  -V, --version                  Print version info and exit
+  -v, --verbose...               Use verbose output (-vv very verbose/build.rs output)
+      --color <WHEN>             Coloring: auto, always, never
+  -h, --help
+          Print help
+
+Commands:
+    build, b    Compile the current package
+    help        Print this message or the help of the given subcommand(s)
+",
+        );
+        assert_eq!(help.options.len(), 4);
+        assert_eq!(help.options[1].long, vec!["verbose"]);
+        assert!(help.options[2].takes_value());
+        assert_eq!(help.options[3].description.as_deref(), Some("Print help"));
+        assert_eq!(
+            help.subcommands,
+            vec![
+                (
+                    "build".to_string(),
+                    Some("Compile the current package".to_string())
+                ),
+                (
+                    "help".to_string(),
+                    Some("Print this message or the help of the given subcommand(s)".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_cobra_and_argparse() {
+        let help = parse(
+            "Available Commands:
+  completion  Generate the autocompletion script for the specified shell
+
+Flags:
+      --config string   config file (default is $HOME/.mockery.yaml)
+",
+        );
+        assert_eq!(help.subcommands[0].0, "completion");
+        assert!(help.options[0].takes_value());
+
+        let help = parse(
+            "usage: mockery [-h] {build,run} ...
+
+positional arguments:
+  {build,run}
+    build      build it
+    run        run it
+
+options:
+  -h, --help  show this help message and exit
+",
+        );
+        assert_eq!(
+            help.subcommands
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["build", "run"]
+        );
+        assert_eq!(help.subcommands[1].1.as_deref(), Some("run it"));
+        assert_eq!(help.options[0].short, vec!["h"]);
+    }
+}
@@ -0,0 +1,410 @@
+//! A reader for KDL documents (<https://kdl.dev>), of either version, which
+//! keeps just what's needed to read specs written in it: the names,
+//! arguments, properties and children of the nodes. Type annotations are
+//! skipped, and numbers are kept as they're written.
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+
+/// A value given to a node, as an argument or a property.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Value {
+    String(String),
+    Number(String),
+    Bool(bool),
+    Null,
+}
+
+impl Value {
+    pub(super) fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(it) => Some(it),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(super) struct Node {
+    pub(super) name: String,
+    pub(super) arguments: Vec<Value>,
+    pub(super) properties: HashMap<String, Value>,
+    pub(super) children: Vec<Node>,
+}
+
+impl Node {
+    /// The string arguments of this node.
+    pub(super) fn strings(&self) -> impl Iterator<Item = &str> {
+        self.arguments.iter().filter_map(Value::as_str)
+    }
+
+    /// The first argument of this node, if it's a string.
+    pub(super) fn first(&self) -> Option<&str> {
+        self.arguments.first().and_then(Value::as_str)
+    }
+
+    /// The property `name` of this node, if it's a string.
+    pub(super) fn string(&self, name: &str) -> Option<&str> {
+        self.properties.get(name).and_then(Value::as_str)
+    }
+
+    /// Whether the property `name` of this node is true.
+    pub(super) fn is(&self, name: &str) -> bool {
+        self.properties.get(name) == Some(&Value::Bool(true))
+    }
+}
+
+/// Parse the nodes in the KDL document `text`.
+pub(super) fn parse(text: &str) -> Result<Vec<Node>> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        position: 0,
+    };
+    let nodes = parser.nodes()?;
+    if parser.position < parser.chars.len() {
+        return Err(parser.error("unexpected `}`"));
+    }
+    Ok(nodes)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.position + offset).copied()
+    }
+
+    fn starts_with(&self, prefix: &str) -> bool {
+        prefix
+            .chars()
+            .enumerate()
+            .all(|(i, c)| self.peek_at(i) == Some(c))
+    }
+
+    fn error(&self, message: &str) -> anyhow::Error {
+        let line = self.chars[..self.position.min(self.chars.len())]
+            .iter()
+            .filter(|&&c| c == '\n')
+            .count()
+            + 1;
+        anyhow!("{message} on line {line}")
+    }
+
+    /// Skip whitespace, comments and escaped newlines, up to the end of the
+    /// line.
+    fn skip_inline(&mut self) -> Result<()> {
+        loop {
+            match self.peek() {
+                Some(c) if c != '\n' && c.is_whitespace() => self.position += 1,
+                Some('\\') => {
+                    self.position += 1;
+                    self.skip_inline()?;
+                    match self.peek() {
+                        Some('\n') => self.position += 1,
+                        None => (),
+                        _ => return Err(self.error("expected a newline after `\\`")),
+                    }
+                }
+                Some('/') if self.starts_with("//") => {
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.position += 1;
+                    }
+                }
+                Some('/') if self.starts_with("/*") => self.block_comment()?,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Skip a (possibly nested) block comment.
+    fn block_comment(&mut self) -> Result<()> {
+        let mut depth = 0;
+        loop {
+            if self.starts_with("/*") {
+                depth += 1;
+                self.position += 2;
+            } else if self.starts_with("*/") {
+                depth -= 1;
+                self.position += 2;
+                if depth == 0 {
+                    return Ok(());
+                }
+            } else if self.peek().is_some() {
+                self.position += 1;
+            } else {
+                return Err(self.error("unterminated comment"));
+            }
+        }
+    }
+
+    /// Skip everything which can come between nodes.
+    fn skip_lines(&mut self) -> Result<()> {
+        loop {
+            self.skip_inline()?;
+            match self.peek() {
+                Some('\n' | ';') => self.position += 1,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Skip a `/-`, which comments out what follows it.
+    fn slashdash(&mut self) -> Result<bool> {
+        if !self.starts_with("/-") {
+            return Ok(false);
+        }
+        self.position += 2;
+        self.skip_lines()?;
+        Ok(true)
+    }
+
+    fn nodes(&mut self) -> Result<Vec<Node>> {
+        let mut nodes = vec![];
+        loop {
+            self.skip_lines()?;
+            if matches!(self.peek(), None | Some('}')) {
+                return Ok(nodes);
+            }
+            let discard = self.slashdash()?;
+            let node = self.node()?;
+            if !discard {
+                nodes.push(node);
+            }
+        }
+    }
+
+    fn node(&mut self) -> Result<Node> {
+        self.type_annotation()?;
+        let mut node = match self.value()? {
+            Value::String(name) => Node {
+                name,
+                ..Node::default()
+            },
+            _ => return Err(self.error("expected the name of a node")),
+        };
+        loop {
+            self.skip_inline()?;
+            let discard = self.slashdash()?;
+            match self.peek() {
+                None | Some('\n' | ';' | '}') => return Ok(node),
+                Some('{') => {
+                    self.position += 1;
+                    let children = self.nodes()?;
+                    if self.peek() != Some('}') {
+                        return Err(self.error("expected `}`"));
+                    }
+                    self.position += 1;
+                    if !discard {
+                        node.children.extend(children);
+                    }
+                }
+                _ => {
+                    self.type_annotation()?;
+                    let value = self.value()?;
+                    if self.peek() == Some('=') {
+                        self.position += 1;
+                        self.type_annotation()?;
+                        let property = self.value()?;
+                        let Value::String(key) = value else {
+                            return Err(self.error("expected the name of a property"));
+                        };
+                        if !discard {
+                            node.properties.insert(key, property);
+                        }
+                    } else if !discard {
+                        node.arguments.push(value);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Skip a type annotation, like `(u8)`.
+    fn type_annotation(&mut self) -> Result<()> {
+        if self.peek() == Some('(') {
+            self.position += 1;
+            self.value()?;
+            if self.peek() != Some(')') {
+                return Err(self.error("expected `)`"));
+            }
+            self.position += 1;
+        }
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        match (self.peek(), self.peek_at(1)) {
+            (Some('"'), _) => self.string().map(Value::String),
+            (Some('r'), Some('"' | '#')) => {
+                self.position += 1;
+                self.raw_string().map(Value::String)
+            }
+            (Some('#'), Some('"' | '#')) => self.raw_string().map(Value::String),
+            _ => {
+                let start = self.position;
+                while let Some(c) = self.peek() && !c.is_whitespace() && !"(){}[]/\\\";=".contains(c) {
+                    self.position += 1;
+                }
+                let word: String = self.chars[start..self.position].iter().collect();
+                Ok(match word.trim_start_matches('#') {
+                    "" => return Err(self.error("expected a value")),
+                    "true" => Value::Bool(true),
+                    "false" => Value::Bool(false),
+                    "null" => Value::Null,
+                    _ if word.starts_with(|c: char| c.is_ascii_digit())
+                        || (word.starts_with(['+', '-', '.'])
+                            && word[1..].starts_with(|c: char| c.is_ascii_digit())) =>
+                    {
+                        Value::Number(word)
+                    }
+                    _ => Value::String(word),
+                })
+            }
+        }
+    }
+
+    /// A quoted string, which may be a multi-line string.
+    fn string(&mut self) -> Result<String> {
+        let multiline = self.starts_with("\"\"\"");
+        self.position += if multiline { 3 } else { 1 };
+        let mut string = String::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unterminated string")),
+                Some('"') if !multiline || self.starts_with("\"\"\"") => {
+                    self.position += if multiline { 3 } else { 1 };
+                    return Ok(if multiline { dedent(&string) } else { string });
+                }
+                Some('\\') => {
+                    self.position += 1;
+                    let Some(c) = self.peek() else {
+                        return Err(self.error("unterminated string"));
+                    };
+                    self.position += 1;
+                    match c {
+                        'n' => string.push('\n'),
+                        't' => string.push('\t'),
+                        'r' => string.push('\r'),
+                        'b' => string.push('\u{8}'),
+                        'f' => string.push('\u{c}'),
+                        's' => string.push(' '),
+                        'u' => {
+                            let end = self.chars[self.position..]
+                                .iter()
+                                .position(|&c| c == '}')
+                                .ok_or_else(|| self.error("unterminated unicode escape"))?;
+                            let digits: String = self.chars[self.position + 1..self.position + end]
+                                .iter()
+                                .collect();
+                            self.position += end + 1;
+                            string.extend(
+                                u32::from_str_radix(&digits, 16)
+                                    .ok()
+                                    .and_then(char::from_u32),
+                            );
+                        }
+                        // Escaped whitespace is left out.
+                        c if c.is_whitespace() => {
+                            while self.peek().map_or(false, char::is_whitespace) {
+                                self.position += 1;
+                            }
+                        }
+                        c => string.push(c),
+                    }
+                }
+                Some(c) => {
+                    string.push(c);
+                    self.position += 1;
+                }
+            }
+        }
+    }
+
+    /// A raw string, like `#"C:\"#` (or `r#"C:\"#` in version 1), without
+    /// escapes.
+    fn raw_string(&mut self) -> Result<String> {
+        let hashes = self.chars[self.position..]
+            .iter()
+            .take_while(|&&c| c == '#')
+            .count();
+        self.position += hashes;
+        let multiline = self.starts_with("\"\"\"");
+        let quotes = if multiline { 3 } else { 1 };
+        if self.peek() != Some('"') {
+            return Err(self.error("expected `\"`"));
+        }
+        self.position += quotes;
+        let end = format!("{}{}", "\"".repeat(quotes), "#".repeat(hashes));
+        let start = self.position;
+        while !self.starts_with(&end) {
+            if self.peek().is_none() {
+                return Err(self.error("unterminated raw string"));
+            }
+            self.position += 1;
+        }
+        let string: String = self.chars[start..self.position].iter().collect();
+        self.position += end.chars().count();
+        Ok(if multiline { dedent(&string) } else { string })
+    }
+}
+
+/// The text of a multi-line string, without its first and last lines and
+/// with the indentation of the last line removed from every line.
+fn dedent(text: &str) -> String {
+    let text = text.strip_prefix('\n').unwrap_or(text);
+    let (body, indent) = text.rsplit_once('\n').unwrap_or(("", text));
+    body.lines()
+        .map(|line| line.strip_prefix(indent).unwrap_or(line.trim_start()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let nodes = parse(
+            r##"// a comment
+name "mockery" /* another */ version=1.0
+/-ignored "node"
+flag "-v --verbose" help=#"Be "verbose""# count=#true { /- choices a
+    choices a "b c" \
+        d
+}; cmd build hide=true
+about """
+    Line one
+      Line two
+    """
+"##,
+        )?;
+        assert_eq!(nodes.len(), 4);
+        assert_eq!(nodes[0].first(), Some("mockery"));
+        assert_eq!(
+            nodes[0].properties["version"],
+            Value::Number("1.0".to_string())
+        );
+        assert_eq!(nodes[1].name, "flag");
+        assert_eq!(nodes[1].string("help"), Some(r#"Be "verbose""#));
+        assert!(nodes[1].is("count"));
+        assert_eq!(nodes[1].children.len(), 1);
+        assert_eq!(
+            nodes[1].children[0].strings().collect::<Vec<_>>(),
+            vec!["a", "b c", "d"]
+        );
+        assert_eq!(nodes[2].first(), Some("build"));
+        assert!(nodes[2].is("hide"));
+        assert_eq!(nodes[3].first(), Some("Line one\n  Line two"));
+        assert!(parse("flag \"-v").is_err());
+        assert!(parse("cmd a }").is_err());
+        Ok(())
+    }
+}
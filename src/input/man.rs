@@ -0,0 +1,350 @@
+//! Generate completions from man pages, so that fish isn't needed to generate
+//! them first.
+//!
+//! Only the parts of roff (and mdoc) which are used to list options are
+//! understood: the options are taken from the tags of the `.TP`, `.IP` and
+//! `.It` paragraphs in the OPTIONS and DESCRIPTION sections, and the first
+//! sentence of each paragraph is taken as the option's description.
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+use flate2::read::GzDecoder;
+use log::{debug, trace};
+
+use super::{first_sentence, parse_option_spec};
+use crate::{completion_line::CompletionLine, completions::Completions};
+
+/// The command a man page documents, taken from its file name (e.g. `ls` for
+/// `ls.1.gz`).
+pub(crate) fn command_name(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    let name = name.strip_suffix(".gz").unwrap_or(name);
+    let (command, _section) = name.rsplit_once('.')?;
+    (!command.is_empty()).then(|| command.to_string())
+}
+
+/// Read the man page at `path`, decompressing it if it's gzipped.
+fn read(path: &Path) -> Result<String> {
+    let mut contents = vec![];
+    let file = File::open(path)?;
+    match path.extension().and_then(|it| it.to_str()) {
+        Some("gz") => GzDecoder::new(file).read_to_end(&mut contents)?,
+        Some(compression @ ("bz2" | "xz" | "lzma" | "zst")) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{compression} compressed man pages aren't supported: {path:?}"),
+            )
+            .into())
+        }
+        _ => io::BufReader::new(file).read_to_end(&mut contents)?,
+    };
+    Ok(String::from_utf8_lossy(&contents).into_owned())
+}
+
+/// Parse the options documented in the man page at `path`.
+pub(crate) fn parse_file(path: &Path) -> Result<Completions> {
+    let command =
+        command_name(path).ok_or_else(|| anyhow!("{path:?} isn't named like a man page"))?;
+    parse(&command, &read(path)?)
+}
+
+/// Parse the options for `command` documented in the man page `page`.
+pub(crate) fn parse(command: &str, page: &str) -> Result<Completions> {
+    let mut parser = Parser::default();
+    for line in page.lines() {
+        parser.line(line);
+    }
+    parser.flush();
+    let completions = Completions::default();
+    let mut added: Vec<CompletionLine> = vec![];
+    for (tag, description) in parser.paragraphs {
+        let Some(mut completion) = parse_option_spec(&tag) else {
+            trace!(tag = tag; "tag doesn't define any options");
+            continue;
+        };
+        completion.command = Some(command.to_string());
+        completion.description = first_sentence(&description);
+        if added.iter().any(|it| it.overlaps(&completion)) {
+            debug!(tag = tag; "skipping option which was already documented");
+            continue;
+        }
+        added.push(completion.clone());
+        completions.add(completion)?;
+    }
+    Ok(completions)
+}
+
+/// The tagged paragraphs in the option sections of a man page.
+#[derive(Default)]
+struct Parser {
+    /// Whether the current section lists options
+    in_options: bool,
+    /// Whether the next line of text is the tag of a `.TP` paragraph
+    expecting_tag: bool,
+    /// The tag of the current paragraph, if it has one
+    tag: Option<String>,
+    /// The text of the current paragraph
+    text: String,
+    /// Each tag in an option section, and the text of its paragraph
+    paragraphs: Vec<(String, String)>,
+}
+
+impl Parser {
+    /// End the current paragraph.
+    fn flush(&mut self) {
+        if let Some(tag) = self.tag.take() && self.in_options {
+            self.paragraphs.push((tag, std::mem::take(&mut self.text)));
+        }
+        self.text.clear();
+        self.expecting_tag = false;
+    }
+
+    fn line(&mut self, line: &str) {
+        let text = if let Some(request) = line.strip_prefix('.').or_else(|| line.strip_prefix('\''))
+        {
+            if request.starts_with("\\\"") {
+                return;
+            }
+            let (name, args) = request
+                .trim_start()
+                .split_once(char::is_whitespace)
+                .unwrap_or((request.trim_start(), ""));
+            let args = macro_args(args);
+            match name {
+                "SH" | "Sh" => {
+                    self.flush();
+                    let title = unescape(&args.join(" ")).to_uppercase();
+                    self.in_options = title.contains("OPTION") || title == "DESCRIPTION";
+                    trace!(section = title, options = self.in_options; "section");
+                    return;
+                }
+                "SS" | "Ss" | "PP" | "LP" | "P" | "Pp" | "HP" => {
+                    self.flush();
+                    return;
+                }
+                "TP" => {
+                    self.flush();
+                    self.expecting_tag = true;
+                    return;
+                }
+                "IP" => {
+                    self.flush();
+                    self.tag = args.first().map(|tag| unescape(tag));
+                    return;
+                }
+                "It" => {
+                    self.flush();
+                    self.tag = Some(mdoc_text(&args));
+                    return;
+                }
+                "B" | "I" | "SM" | "SB" => unescape(&args.join(" ")),
+                "BR" | "BI" | "IB" | "IR" | "RB" | "RI" => unescape(&args.concat()),
+                // mdoc macros which are inline text
+                "Fl" | "Ar" | "Op" | "Cm" | "Nm" | "Xr" | "Pa" | "Li" | "Em" | "Sy" | "Dq"
+                | "Ql" | "Va" | "Ev" => {
+                    mdoc_text(&[name].into_iter().chain(args).collect::<Vec<_>>())
+                }
+                _ => return,
+            }
+        } else {
+            unescape(line)
+        };
+        if !self.in_options {
+            return;
+        }
+        if self.expecting_tag {
+            self.tag = Some(text);
+            self.expecting_tag = false;
+        } else if self.tag.is_some() {
+            if !self.text.is_empty() {
+                self.text.push(' ');
+            }
+            self.text.push_str(text.trim());
+        }
+    }
+}
+
+/// Split the arguments to a macro on whitespace, except within double
+/// quotes.
+fn macro_args(args: &str) -> Vec<&str> {
+    let mut result = vec![];
+    let mut rest = args.trim_start();
+    while !rest.is_empty() {
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            result.push(&quoted[..end]);
+            rest = quoted.get(end + 1..).unwrap_or("").trim_start();
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            result.push(&rest[..end]);
+            rest = rest[end..].trim_start();
+        }
+    }
+    result
+}
+
+/// The text of a line of mdoc macros, like `Fl a Ar file`, which is `-a file`.
+fn mdoc_text(args: &[&str]) -> String {
+    let mut words = vec![];
+    let mut flag = false;
+    for arg in args {
+        let is_macro = arg.len() == 2
+            && arg.starts_with(|c: char| c.is_ascii_uppercase())
+            && arg.ends_with(|c: char| c.is_ascii_lowercase());
+        if *arg == "Fl" {
+            flag = true;
+        } else if is_macro {
+            flag = false;
+        } else if flag {
+            words.push(format!("-{}", unescape(arg)));
+            flag = false;
+        } else {
+            words.push(unescape(arg));
+        }
+    }
+    words.join(" ")
+}
+
+/// The glyph named by a roff special character escape, like `\(em`.
+fn glyph(name: &str) -> &'static str {
+    match name {
+        "em" | "en" | "hy" | "mi" => "-",
+        "aq" | "cq" | "oq" => "'",
+        "dq" | "lq" | "rq" | "Lq" | "Rq" => "\"",
+        "bu" => "*",
+        "ti" => "~",
+        "ha" => "^",
+        "rs" => "\\",
+        "ba" | "or" => "|",
+        "lB" => "[",
+        "rB" => "]",
+        _ => "",
+    }
+}
+
+/// Remove the roff escapes from `text`, replacing those which represent
+/// characters with those characters.
+fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    // The name of an escape's argument, like the `B` in `\fB`, the `em` in
+    // `\(em` or the `em` in `\[em]`.
+    let name = |chars: &mut std::iter::Peekable<std::str::Chars>| -> String {
+        match chars.next() {
+            Some('(') => chars.by_ref().take(2).collect(),
+            Some('[') => chars.by_ref().take_while(|&c| c != ']').collect(),
+            Some(c) => c.to_string(),
+            None => String::new(),
+        }
+    };
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('-') => result.push('-'),
+            Some('e') | Some('\\') => result.push('\\'),
+            Some(' ') | Some('~') | Some('0') => result.push(' '),
+            Some('"') => break,
+            Some('(') => {
+                let name: String = chars.by_ref().take(2).collect();
+                result.push_str(glyph(&name));
+            }
+            Some('[') => {
+                let name: String = chars.by_ref().take_while(|&c| c != ']').collect();
+                result.push_str(glyph(&name));
+            }
+            Some('f') | Some('*') | Some('n') | Some('F') => {
+                name(&mut chars);
+            }
+            Some('s') => {
+                if matches!(chars.peek(), Some('+') | Some('-')) {
+                    chars.next();
+                }
+                while chars.peek().map_or(false, char::is_ascii_digit) {
+                    chars.next();
+                }
+            }
+            Some(c) if c.is_alphanumeric() => {
+                // Other escapes with arguments, which don't produce text
+                if chars.peek() == Some(&'\'') {
+                    chars.next();
+                    chars.by_ref().take_while(|&c| c != '\'').for_each(drop);
+                }
+            }
+            // \& \% \: \| \^ \) \, \/ and friends are zero-width
+            Some(_) | None => {}
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: &str = r#".\" a comment
+.TH LS "1" "March 2024" "GNU coreutils 9.5" "User Commands"
+.SH NAME
+ls \- list directory contents
+.SH DESCRIPTION
+List information about the FILEs.
+.TP
+\fB\-a\fR, \fB\-\-all\fR
+do not ignore entries starting with .
+.TP
+\fB\-\-block\-size\fR=\fI\,SIZE\/\fR
+with \fB\-l\fR, scale sizes by SIZE when printing them. See SIZE format below
+.TP
+\fB\-\-color\fR[=\fI\,WHEN\/\fR]
+color the output WHEN; more info below
+.IP "\fB\-w\fR \fICOLS\fR" 4
+set output width to COLS.
+.TP
+\fB\-a\fR
+documented twice
+.SH "SEE ALSO"
+.TP
+\fB\-\-not\-an\-option\fR
+"#;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let completions = parse("mockery", PAGE)?;
+        let completions = completions.read().expect("rwlock read access");
+        let options = &completions[String::from("mockery")];
+        assert_eq!(options.len(), 4);
+        assert_eq!(options[0].short, vec!["a"]);
+        assert_eq!(options[0].long, vec!["all"]);
+        assert_eq!(
+            options[0].description.as_deref(),
+            Some("do not ignore entries starting with")
+        );
+        assert_eq!(options[1].long, vec!["block-size"]);
+        assert!(options[1].takes_value());
+        assert_eq!(
+            options[1].description.as_deref(),
+            Some("with -l, scale sizes by SIZE when printing them")
+        );
+        assert_eq!(options[2].long, vec!["color"]);
+        assert!(!options[2].takes_value());
+        assert_eq!(options[3].short, vec!["w"]);
+        assert!(options[3].takes_value());
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_name() {
+        assert_eq!(
+            command_name(Path::new("/usr/share/man/man8/mkfs.ext4.8.gz")).as_deref(),
+            Some("mkfs.ext4")
+        );
+        assert_eq!(command_name(Path::new("ls.1")).as_deref(), Some("ls"));
+        assert_eq!(command_name(Path::new("README")), None);
+    }
+}
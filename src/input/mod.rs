@@ -0,0 +1,129 @@
+//! The formats completions can be generated from (see [`InputFormat`]).
+pub(crate) mod argparse;
+pub(crate) mod bash;
+pub(crate) mod carapace;
+mod fish;
+pub(crate) mod help;
+mod kdl;
+pub(crate) mod man;
+mod shell;
+pub(crate) mod usage;
+pub(crate) mod zsh;
+
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::{
+    completion_line::CompletionLine,
+    completions::Completions,
+    config::{Config, InputFormat},
+    dir_walker::list_files,
+    ir,
+};
+
+/// The condition fish uses for completions which only apply before a
+/// subcommand has been given, so that [`crate::complete`] can offer the
+/// subcommands.
+pub(crate) const USE_SUBCOMMAND: &str = "__fish_use_subcommand";
+
+impl InputFormat {
+    /// The file name of the definition generated from the source at `path`,
+    /// which is named after the command the source defines completions for.
+    pub(crate) fn definition_file_name(self, path: &Path) -> Option<OsString> {
+        match self {
+            Self::Fish => path.with_extension("nu").file_name().map(ToOwned::to_owned),
+            Self::Man => man::command_name(path).map(|name| format!("{name}.nu").into()),
+            Self::Help => help::command_name(path).map(|name| format!("{name}.nu").into()),
+            Self::Zsh => zsh::command_name(path).map(|name| format!("{name}.nu").into()),
+            Self::Bash => bash::command_name(path).map(|name| format!("{name}.nu").into()),
+            Self::Usage => usage::command_name(path).map(|name| format!("{name}.nu").into()),
+            Self::Carapace => carapace::command_name(path).map(|name| format!("{name}.nu").into()),
+            Self::Argparse => argparse::command_name(path).map(|name| format!("{name}.nu").into()),
+            Self::Ir => path.with_extension("nu").file_name().map(ToOwned::to_owned),
+        }
+    }
+
+    /// The sources in `source`: every file in it if it's a directory, or
+    /// otherwise just `source`. For [`InputFormat::Help`], sources which don't
+    /// exist are commands to be run.
+    pub(crate) fn source_files(self, source: &Path) -> Result<Vec<PathBuf>> {
+        if self == Self::Help && !source.exists() {
+            Ok(vec![source.to_path_buf()])
+        } else {
+            list_files(source)
+        }
+    }
+
+    /// Parse the completions defined by the source at `path`.
+    pub(crate) fn parse(self, path: &Path) -> Result<Completions> {
+        match self {
+            Self::Fish => fish::parse_file(path),
+            Self::Man => man::parse_file(path),
+            Self::Help => help::parse_file(path, Config::help_depth(), Config::help_timeout()),
+            Self::Zsh => zsh::parse_file(path),
+            Self::Bash => bash::parse_file(path),
+            Self::Usage => usage::parse_file(path),
+            Self::Carapace => carapace::parse_file(path),
+            Self::Argparse => argparse::parse_file(path),
+            Self::Ir => ir::parse_file(path),
+        }
+    }
+}
+
+/// Parse the options in a specification like `-w, --width=COLS`, as found
+/// in the tag of a man page paragraph or at the start of a line of help.
+pub(super) fn parse_option_spec(tag: &str) -> Option<CompletionLine> {
+    let mut completion = CompletionLine::default();
+    let mut takes_value = false;
+    for word in tag.split(|c: char| c == ',' || c.is_whitespace()) {
+        let word = word.trim_end_matches(['.', ';', ':']);
+        if word.is_empty() || word == "or" {
+            continue;
+        }
+        let dashes = word.len() - word.trim_start_matches('-').len();
+        if dashes == 0 {
+            // An argument to the option, like the `COLS` in `-w COLS`.
+            takes_value |= !completion.short.is_empty()
+                || !completion.long.is_empty()
+                || !completion.old_option.is_empty();
+            continue;
+        }
+        let option = &word[dashes..];
+        let end = option.find(['=', '[', '<']).unwrap_or(option.len());
+        let (name, value) = option.split_at(end);
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_alphanumeric() || "-_.?#@".contains(c))
+        {
+            continue;
+        }
+        match dashes {
+            1 if name.chars().count() == 1 => completion.short.push(name.to_string()),
+            1 => completion.old_option.push(name.to_string()),
+            2 => completion.long.push(name.to_string()),
+            _ => continue,
+        }
+        takes_value |= value.starts_with('=') || value.starts_with('<');
+    }
+    if completion.short.is_empty() && completion.long.is_empty() && completion.old_option.is_empty()
+    {
+        return None;
+    }
+    completion.require_parameter = takes_value;
+    Some(completion)
+}
+
+/// The first sentence of `text`, without its full stop.
+pub(super) fn first_sentence(text: &str) -> Option<String> {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let sentence = match text.find(". ") {
+        Some(end) => &text[..end],
+        None => text.trim_end_matches('.').trim_end(),
+    };
+    (!sentence.is_empty()).then(|| sentence.to_string())
+}
@@ -0,0 +1,411 @@
+//! Just enough of a lexer and parser for zsh and bash to read completion
+//! functions: the words of each command, and the functions and `case`s it's
+//! inside of.
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+/// A token of a script.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Token {
+    /// A word, as each of the words it's brace expanded to
+    Word(Vec<String>),
+    /// The start of the elements of an array, like the `(` in `commands=(`
+    ArrayStart,
+    /// The end of the elements of an array
+    ArrayEnd,
+    /// The end of a command, like a newline, `;`, `|` or `&&`
+    Separator,
+    /// The end of a branch of a `case`, `;;`
+    EndBranch,
+}
+
+/// The index of the `close` matching the `open` at `chars[start]`, or the end
+/// of `chars` if there isn't one.
+fn matching(chars: &[char], start: usize, open: char, close: char) -> usize {
+    let mut depth = 0;
+    for (i, &c) in chars.iter().enumerate().skip(start) {
+        if c == open {
+            depth += 1;
+        } else if c == close {
+            depth -= 1;
+            if depth == 0 {
+                return i;
+            }
+        }
+    }
+    chars.len()
+}
+
+/// The alternatives of the brace expansion (like `{-v,--verbose}`) starting
+/// at `chars[start]`, and the index after its end, if there is one there.
+fn brace_expansion(chars: &[char], start: usize) -> Option<(Vec<String>, usize)> {
+    let end = start + chars[start..].iter().position(|&c| c == '}')?;
+    let inner: String = chars[start + 1..end].iter().collect();
+    if !inner.contains(',') || inner.contains(|c: char| c.is_whitespace() || "{'\"$\\".contains(c))
+    {
+        return None;
+    }
+    Some((inner.split(',').map(str::to_string).collect(), end + 1))
+}
+
+/// Split `script` into [`Token`]s, removing quotes and expanding braces, but
+/// otherwise keeping expansions (like `$(...)`) as they're written.
+pub(super) fn lex(script: &str) -> Vec<Token> {
+    let chars: Vec<char> = script.chars().collect();
+    let mut tokens = vec![];
+    let mut word: Option<Vec<String>> = None;
+    let mut in_array = false;
+    let push = |word: &mut Option<Vec<String>>, text: &str| {
+        for alternative in word.get_or_insert_with(|| vec![String::new()]) {
+            alternative.push_str(text);
+        }
+    };
+    let end = |word: &mut Option<Vec<String>>, tokens: &mut Vec<Token>| {
+        if let Some(word) = word.take() {
+            tokens.push(Token::Word(word));
+        }
+    };
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let text =
+            |from: usize, to: usize| chars[from..to.min(chars.len())].iter().collect::<String>();
+        match c {
+            '\\' => {
+                if let Some(next) = next && next != '\n' {
+                    push(&mut word, &next.to_string());
+                }
+                i += 2;
+            }
+            '\'' => {
+                let close = chars[i + 1..]
+                    .iter()
+                    .position(|&c| c == '\'')
+                    .map_or(chars.len(), |it| i + 1 + it);
+                push(&mut word, &text(i + 1, close));
+                i = close + 1;
+            }
+            '"' => {
+                let mut quoted = String::new();
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    if chars[i] == '\\' && matches!(chars.get(i + 1), Some('"' | '\\' | '$' | '`'))
+                    {
+                        i += 1;
+                    }
+                    quoted.push(chars[i]);
+                    i += 1;
+                }
+                push(&mut word, &quoted);
+                i += 1;
+            }
+            '$' if next == Some('(') || next == Some('{') => {
+                let (open, close) = if next == Some('(') {
+                    ('(', ')')
+                } else {
+                    ('{', '}')
+                };
+                let close = matching(&chars, i + 1, open, close);
+                push(&mut word, &text(i, close + 1));
+                i = close + 1;
+            }
+            '`' => {
+                let close = chars[i + 1..]
+                    .iter()
+                    .position(|&c| c == '`')
+                    .map_or(chars.len(), |it| i + 1 + it);
+                push(&mut word, &text(i, close + 1));
+                i = close + 1;
+            }
+            '#' if word.is_none() => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '{' if brace_expansion(&chars, i).is_some() => {
+                let (alternatives, after) = brace_expansion(&chars, i).expect("checked above");
+                let prefixes = word.take().unwrap_or_else(|| vec![String::new()]);
+                word = Some(
+                    prefixes
+                        .iter()
+                        .flat_map(|prefix| {
+                            alternatives.iter().map(move |it| format!("{prefix}{it}"))
+                        })
+                        .collect(),
+                );
+                i = after;
+            }
+            '{' | '}' if word.is_none() && next.map_or(true, char::is_whitespace) => {
+                tokens.push(Token::Word(vec![c.to_string()]));
+                i += 1;
+            }
+            '(' if next == Some(')') => {
+                push(&mut word, "()");
+                end(&mut word, &mut tokens);
+                i += 2;
+            }
+            '(' if word
+                .as_ref()
+                .map_or(false, |word| word.iter().all(|it| it.ends_with('='))) =>
+            {
+                end(&mut word, &mut tokens);
+                tokens.push(Token::ArrayStart);
+                in_array = true;
+                i += 1;
+            }
+            ')' if in_array => {
+                end(&mut word, &mut tokens);
+                tokens.push(Token::ArrayEnd);
+                in_array = false;
+                i += 1;
+            }
+            ';' => {
+                end(&mut word, &mut tokens);
+                if matches!(next, Some(';' | '&' | '|')) {
+                    tokens.push(Token::EndBranch);
+                    i += 2;
+                } else {
+                    tokens.push(Token::Separator);
+                    i += 1;
+                }
+            }
+            // Within a word (like `2>&1` or a case pattern like `a|b)`),
+            // these aren't separators.
+            '&' | '|'
+                if word.is_none() || next == Some(c) || next.map_or(true, char::is_whitespace) =>
+            {
+                end(&mut word, &mut tokens);
+                tokens.push(Token::Separator);
+                i += if next == Some(c) { 2 } else { 1 };
+            }
+            '\n' => {
+                end(&mut word, &mut tokens);
+                if !in_array {
+                    tokens.push(Token::Separator);
+                }
+                i += 1;
+            }
+            c if c.is_whitespace() => {
+                end(&mut word, &mut tokens);
+                i += 1;
+            }
+            c => {
+                push(&mut word, &c.to_string());
+                i += 1;
+            }
+        }
+    }
+    end(&mut word, &mut tokens);
+    tokens
+}
+
+/// A part of a script which a statement is inside of.
+pub(super) enum Frame {
+    /// The body of a function, which started at the given depth of braces
+    Function { name: String, depth: usize },
+    /// A `case` on `subject`, and the pattern of the current branch (like
+    /// `a|b`), if there is one
+    Case {
+        subject: String,
+        pattern: Option<String>,
+    },
+}
+
+/// A statement of a script.
+pub(super) enum Statement<'a> {
+    /// A command, made up of its words
+    Command(&'a [Vec<String>]),
+    /// An assignment to an array, like `commands=(a b)` or `commands+=(c)`
+    Array {
+        name: &'a str,
+        elements: &'a [Vec<String>],
+    },
+}
+
+/// Where a statement is in a script.
+#[derive(Default)]
+pub(super) struct Scope {
+    /// What the statement is inside of, outermost first
+    pub(super) frames: Vec<Frame>,
+    /// The elements of each array which has been assigned to before the
+    /// statement
+    pub(super) arrays: HashMap<String, Vec<Vec<String>>>,
+    /// The depth of the braces around the statement
+    depth: usize,
+    /// The function whose body starts at the next `{`
+    pending_function: Option<String>,
+}
+
+impl Scope {
+    /// End the current branch of the innermost `case`.
+    fn end_branch(&mut self) {
+        if let Some(Frame::Case { pattern, .. }) = self.frames.last_mut() {
+            *pattern = None;
+        }
+    }
+
+    /// Close the innermost brace, ending the function it started, if any.
+    fn close_brace(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+        let depth = self.depth;
+        if let Some(end) = self
+            .frames
+            .iter()
+            .position(|frame| matches!(frame, Frame::Function { depth: it, .. } if *it > depth))
+        {
+            self.frames.truncate(end);
+        }
+    }
+
+    /// Follow the structure of the command made up of `words`, then call `f`
+    /// with whatever's left of it.
+    fn command(
+        &mut self,
+        mut words: Vec<Vec<String>>,
+        f: &mut impl FnMut(&Scope, Statement) -> Result<()>,
+    ) -> Result<()> {
+        while let Some(first) = words.first() {
+            let first = first[0].as_str();
+            match first {
+                "{" => {
+                    self.depth += 1;
+                    if let Some(name) = self.pending_function.take() {
+                        self.frames.push(Frame::Function {
+                            name,
+                            depth: self.depth,
+                        });
+                    }
+                }
+                "}" => self.close_brace(),
+                "case" => {
+                    let subject = words.get(1).map_or("", |it| it[0].as_str());
+                    self.frames.push(Frame::Case {
+                        subject: subject.to_string(),
+                        pattern: None,
+                    });
+                    return Ok(());
+                }
+                "esac" => {
+                    if let Some(end) = self
+                        .frames
+                        .iter()
+                        .rposition(|frame| matches!(frame, Frame::Case { .. }))
+                    {
+                        self.frames.truncate(end);
+                    }
+                }
+                "function" => {
+                    self.pending_function = words
+                        .get(1)
+                        .map(|it| it[0].trim_end_matches("()").to_string());
+                    words.remove(0);
+                }
+                "if" | "then" | "else" | "elif" | "while" | "until" | "do" | "!" => {}
+                _ if first.len() > 2 && first.ends_with("()") => {
+                    self.pending_function = Some(first.trim_end_matches("()").to_string());
+                }
+                _ if first.ends_with(')')
+                    && matches!(self.frames.last(), Some(Frame::Case { .. })) =>
+                {
+                    let pattern = first.trim_start_matches('(').trim_end_matches(')');
+                    if let Some(Frame::Case {
+                        pattern: current, ..
+                    }) = self.frames.last_mut()
+                    {
+                        *current = Some(pattern.to_string());
+                    }
+                }
+                _ => break,
+            }
+            words.remove(0);
+        }
+        let mut closing = 0;
+        while words.last().map_or(false, |it| it[0] == "}") {
+            words.pop();
+            closing += 1;
+        }
+        if !words.is_empty() {
+            f(self, Statement::Command(&words))?;
+        }
+        for _ in 0..closing {
+            self.close_brace();
+        }
+        Ok(())
+    }
+}
+
+/// Call `f` with each statement of `script`, and where it is.
+pub(super) fn walk(script: &str, mut f: impl FnMut(&Scope, Statement) -> Result<()>) -> Result<()> {
+    let mut scope = Scope::default();
+    let mut words = vec![];
+    let mut tokens = lex(script).into_iter();
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Word(word) => words.push(word),
+            Token::ArrayStart => {
+                let mut elements = vec![];
+                for token in tokens.by_ref() {
+                    match token {
+                        Token::Word(word) => elements.push(word),
+                        Token::ArrayEnd => break,
+                        _ => {}
+                    }
+                }
+                let Some(assignment) = words.pop() else {
+                    continue;
+                };
+                let Some(name) = assignment[0].strip_suffix('=') else {
+                    continue;
+                };
+                let (name, append) = match name.strip_suffix('+') {
+                    Some(name) => (name, true),
+                    None => (name, false),
+                };
+                f(
+                    &scope,
+                    Statement::Array {
+                        name,
+                        elements: &elements,
+                    },
+                )?;
+                let array = scope.arrays.entry(name.to_string()).or_default();
+                if !append {
+                    array.clear();
+                }
+                array.extend(elements);
+            }
+            Token::ArrayEnd => {}
+            Token::Separator => scope.command(std::mem::take(&mut words), &mut f)?,
+            Token::EndBranch => {
+                scope.command(std::mem::take(&mut words), &mut f)?;
+                scope.end_branch();
+            }
+        }
+    }
+    scope.command(words, &mut f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lex() {
+        let word = |it: &[&str]| Token::Word(it.iter().map(|it| it.to_string()).collect());
+        assert_eq!(
+            lex("_arguments '(-a)'{-a,--all}\"[all \\\"of\\\" it]\" \\\n  x=(1 2) # c\n"),
+            vec![
+                word(&["_arguments"]),
+                word(&["(-a)-a[all \"of\" it]", "(-a)--all[all \"of\" it]"]),
+                word(&["x="]),
+                Token::ArrayStart,
+                word(&["1"]),
+                word(&["2"]),
+                Token::ArrayEnd,
+                Token::Separator,
+            ]
+        );
+    }
+}
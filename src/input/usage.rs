@@ -0,0 +1,290 @@
+//! Generate completions from CLIs described in the `usage` spec
+//! (<https://usage.jdx.dev>), a KDL document of `cmd`, `flag`, `arg` and
+//! `complete` nodes.
+//!
+//! Flags and subcommands are translated along with their aliases, and the
+//! `choices` of a flag or argument become the values to complete. A value
+//! named like a file or directory (as in `--out <file>`) is typed as a path.
+//! Completions computed by running a command (`complete "x" run="..."`) can't
+//! be translated, so those values are left untyped.
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Result};
+use lazy_regex::regex_captures;
+use log::{debug, warn};
+
+use super::{
+    kdl::{self, Node},
+    parse_option_spec, USE_SUBCOMMAND,
+};
+use crate::{completion_line::CompletionLine, completions::Completions};
+
+/// The command a spec describes, which is the name of the file without its
+/// extension (e.g. `mise` for `mise.usage.kdl`).
+pub(crate) fn command_name(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    let name = name
+        .strip_suffix(".kdl")
+        .map(|it| it.strip_suffix(".usage").unwrap_or(it))
+        .unwrap_or(name);
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Parse the completions defined by the spec at `path`.
+pub(crate) fn parse_file(path: &Path) -> Result<Completions> {
+    let command = command_name(path).ok_or_else(|| anyhow!("{path:?} has no file name"))?;
+    parse(&command, &fs::read_to_string(path)?)
+}
+
+/// Parse the completions defined by `spec`, which describes `command` unless
+/// it names the command it describes.
+pub(crate) fn parse(command: &str, spec: &str) -> Result<Completions> {
+    let nodes = kdl::parse(spec)?;
+    let command = nodes
+        .iter()
+        .find(|it| it.name == "bin")
+        .or_else(|| nodes.iter().find(|it| it.name == "name"))
+        .and_then(Node::first)
+        .filter(|it| !it.contains(char::is_whitespace))
+        .unwrap_or(command);
+    let completions = Completions::default();
+    let globals = nodes
+        .iter()
+        .filter(|it| it.name == "flag" && it.is("global"))
+        .cloned()
+        .collect::<Vec<_>>();
+    add_command(&completions, command, &nodes, &globals)?;
+    Ok(completions)
+}
+
+/// Add the completions for `command`, whose flags, arguments and subcommands
+/// are `nodes`. The `globals` are flags which every subcommand takes too.
+fn add_command(
+    completions: &Completions,
+    command: &str,
+    nodes: &[Node],
+    globals: &[Node],
+) -> Result<()> {
+    let flags = nodes.iter().filter(|it| it.name == "flag");
+    let inherited = globals.iter().filter(|global| {
+        !nodes
+            .iter()
+            .any(|it| it.name == "flag" && it.first() == global.first())
+    });
+    for node in flags.chain(inherited) {
+        if node.is("hide") {
+            continue;
+        }
+        for line in flag(command, node, nodes) {
+            completions.add(line)?;
+        }
+    }
+    for node in nodes.iter().filter(|it| it.name == "arg") {
+        let name = node.first().and_then(value_name);
+        for (value, description) in choices(node)
+            .or_else(|| name.and_then(|name| completed_choices(name, nodes)))
+            .unwrap_or_default()
+        {
+            completions.add(CompletionLine {
+                command: Some(command.to_string()),
+                argument: Some(shell_words::quote(&value).into_owned()),
+                description: description.or_else(|| help(node)),
+                no_files: true,
+                ..CompletionLine::default()
+            })?;
+        }
+    }
+    for node in nodes.iter().filter(|it| it.name == "cmd") {
+        let Some(name) = node.first() else {
+            continue;
+        };
+        let aliases = node
+            .children
+            .iter()
+            .filter(|it| it.name == "alias")
+            .flat_map(|alias| alias.strings().map(move |name| (name, alias.is("hide"))));
+        for (name, hidden) in [(name, node.is("hide"))].into_iter().chain(aliases) {
+            if !hidden {
+                completions.add(CompletionLine {
+                    command: Some(command.to_string()),
+                    argument: Some(shell_words::quote(name).into_owned()),
+                    description: help(node),
+                    condition: Some(USE_SUBCOMMAND.to_string()),
+                    no_files: true,
+                    ..CompletionLine::default()
+                })?;
+            }
+            add_command(
+                completions,
+                &format!("{command} {name}"),
+                &node.children,
+                globals,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// The completions for the flag `node` of `command`, whose other nodes are
+/// `siblings`: the flag, and its negation if it has one.
+fn flag(command: &str, node: &Node, siblings: &[Node]) -> Vec<CompletionLine> {
+    let Some(spec) = node.first() else {
+        return vec![];
+    };
+    let Some(mut line) = parse_option_spec(spec) else {
+        debug!(command = command, spec = spec; "no options in flag spec");
+        return vec![];
+    };
+    for alias in node.children.iter().filter(|it| it.name == "alias") {
+        for alias in alias.strings().filter_map(parse_option_spec) {
+            line.short.extend(alias.short);
+            line.long.extend(alias.long);
+        }
+    }
+    line.command = Some(command.to_string());
+    line.description = help(node);
+    // The value is named in the spec (as in `--out <file>`) or by an `arg`.
+    let value = node
+        .children
+        .iter()
+        .find(|it| it.name == "arg")
+        .and_then(Node::first)
+        .or_else(|| regex_captures!(r"[<\[]([^>\]]+)[>\]]", spec).map(|(_, it)| it))
+        .and_then(value_name);
+    if let Some(value) = value {
+        line.require_parameter = true;
+        line.value_type = value_type(value).map(ToString::to_string);
+        let choices = choices(node)
+            .or_else(|| node.children.iter().find_map(choices))
+            .or_else(|| completed_choices(value, siblings));
+        if let Some(choices) = choices {
+            line.argument = Some(shell_words::join(choices.into_iter().map(|(it, _)| it)));
+            line.no_files = true;
+        }
+    }
+    let negation = node.string("negate").and_then(parse_option_spec);
+    [line]
+        .into_iter()
+        .chain(negation.map(|negation| CompletionLine {
+            command: Some(command.to_string()),
+            ..negation
+        }))
+        .collect()
+}
+
+/// The name of the value in a spec like `<file>`, `[dir]` or `<files>...`.
+fn value_name(spec: &str) -> Option<&str> {
+    let name = spec
+        .trim_end_matches("...")
+        .trim_start_matches(['<', '['])
+        .trim_end_matches(['>', ']'])
+        .trim_end_matches("...");
+    (!name.is_empty()).then_some(name)
+}
+
+/// The nu type of a value named `name`, if it's more specific than a string.
+fn value_type(name: &str) -> Option<&'static str> {
+    match name.to_lowercase().as_str() {
+        "file" | "files" | "path" | "paths" | "filename" => Some("path"),
+        "dir" | "dirs" | "directory" | "directories" => Some("directory"),
+        _ => None,
+    }
+}
+
+/// The values listed by the `choices` among the children of `node`.
+fn choices(node: &Node) -> Option<Vec<(String, Option<String>)>> {
+    let choices: Vec<_> = node
+        .children
+        .iter()
+        .filter(|it| it.name == "choices")
+        .flat_map(Node::strings)
+        .map(|it| (it.to_string(), None))
+        .collect();
+    (!choices.is_empty()).then_some(choices)
+}
+
+/// The choices given by the `complete` node for the value named `name`, among
+/// `nodes`, if they're listed rather than computed.
+fn completed_choices(name: &str, nodes: &[Node]) -> Option<Vec<(String, Option<String>)>> {
+    let node = nodes
+        .iter()
+        .find(|it| it.name == "complete" && it.first() == Some(name))?;
+    if let Some(run) = node.string("run") {
+        warn!(value = name, run = run; "value is completed by running a command, which isn't translated");
+    }
+    choices(node)
+}
+
+/// The help for `node`, given as a property or a child.
+fn help(node: &Node) -> Option<String> {
+    node.string("help")
+        .or_else(|| {
+            node.children
+                .iter()
+                .find(|it| it.name == "help")
+                .and_then(Node::first)
+        })
+        .and_then(|it| it.lines().next())
+        .map(ToString::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: &str = r#"
+bin "mockery"
+flag "-v --verbose" help="Be verbose" global=#true
+flag "--color <when>" help="When to color" negate="--no-color" {
+    choices "auto" "always" "never"
+}
+arg "<shell>" {
+    choices "bash" "zsh"
+}
+cmd "build" help="Build it" {
+    alias "b"
+    alias "bld" hide=#true
+    flag "-o --out <file>" help="Where to write it"
+    flag "--profile <profile>"
+    complete "profile" run="mockery profiles"
+    cmd "secret" hide=#true
+}
+"#;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let completions = parse("spec", SPEC)?;
+        let completions = completions.read().expect("rwlock read access");
+        let root = &completions[String::from("mockery")];
+        assert_eq!(root[0].long, vec!["verbose"]);
+        assert!(!root[0].takes_value());
+        assert_eq!(root[1].values(), vec!["auto", "always", "never"]);
+        assert_eq!(root[2].long, vec!["no-color"]);
+        assert_eq!(root[3].argument.as_deref(), Some("bash"));
+        let subcommands: Vec<_> = root
+            .iter()
+            .filter(|it| it.condition.as_deref() == Some(USE_SUBCOMMAND))
+            .map(|it| it.argument.as_deref().unwrap())
+            .collect();
+        assert_eq!(subcommands, vec!["build", "b"]);
+        for name in ["mockery build", "mockery b", "mockery bld"] {
+            let build = &completions[String::from(name)];
+            assert_eq!(build[0].short, vec!["o"]);
+            assert_eq!(build[0].value_type.as_deref(), Some("path"));
+            assert!(build[1].takes_value());
+            assert_eq!(build[1].argument, None);
+            assert_eq!(build[2].long, vec!["verbose"]);
+        }
+        assert!(completions.contains_key("mockery build secret"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_name() {
+        assert_eq!(
+            command_name(Path::new("specs/mise.usage.kdl")).as_deref(),
+            Some("mise")
+        );
+        assert_eq!(command_name(Path::new("tool.kdl")).as_deref(), Some("tool"));
+    }
+}
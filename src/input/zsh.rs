@@ -0,0 +1,452 @@
+//! Generate completions from zsh completion functions, like those in
+//! `/usr/share/zsh/functions/Completion`.
+//!
+//! Completion functions are shell scripts, so only the usual ways of writing
+//! them are understood:
+//!
+//!  - the specs passed to `_arguments`, like
+//!    `'(-v --verbose)'{-v,--verbose}'[be verbose]'` or
+//!    `'--color=[when to color]:when:(always never auto)'`, including those
+//!    kept in arrays
+//!  - the subcommands listed by `_describe`, from arrays of
+//!    `name:description`s, when it's describing commands
+//!  - the specs for subcommands, in functions named like `_git-add` or in the
+//!    branches of a `case` on `$words[1]` or `$line[1]`
+//!
+//! Value actions which list the values (`(a b c)` or `((a\:A b\:B))`) are kept
+//! as the values to complete. Any other action just means that a value is
+//! required.
+use std::{collections::HashSet, fs, path::Path};
+
+use anyhow::{anyhow, Result};
+use lazy_regex::{regex_captures, regex_is_match};
+use log::{debug, trace};
+
+use super::{
+    parse_option_spec,
+    shell::{walk, Frame, Scope, Statement},
+    USE_SUBCOMMAND,
+};
+use crate::{completion_line::CompletionLine, completions::Completions};
+
+/// The command a completion function completes, which is its name without
+/// the leading underscore (e.g. `git` for `_git`).
+pub(crate) fn command_name(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    let name = name.strip_prefix('_').unwrap_or(name);
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Parse the completions defined by the completion function at `path`.
+pub(crate) fn parse_file(path: &Path) -> Result<Completions> {
+    let command = command_name(path).ok_or_else(|| anyhow!("{path:?} has no file name"))?;
+    parse(&command, &String::from_utf8_lossy(&fs::read(path)?))
+}
+
+/// Parse the completions for `command` defined by the completion function
+/// `script`. Functions without a `#compdef` line are helpers rather than the
+/// completions for a command, so they define nothing.
+pub(crate) fn parse(command: &str, script: &str) -> Result<Completions> {
+    let compdef = script
+        .lines()
+        .next()
+        .and_then(|it| it.strip_prefix("#compdef"));
+    if !compdef.map_or(false, |it| {
+        it.starts_with(' ') && !it.trim().starts_with('-')
+    }) {
+        debug!(command = command; "not a completion function for a command");
+        return Ok(Completions::default());
+    }
+    let mut parser = Parser::new(command);
+    walk(script, |scope, statement| match statement {
+        Statement::Command(words) => match words.first().map(|it| it[0].as_str()) {
+            Some("_arguments") => parser.arguments(scope, &words[1..]),
+            Some("_describe") => parser.describe(scope, &words[1..]),
+            _ => Ok(()),
+        },
+        Statement::Array { .. } => Ok(()),
+    })?;
+    Ok(parser.completions)
+}
+
+/// A spec passed to `_arguments`.
+#[derive(Debug, PartialEq, Eq)]
+enum Spec {
+    Option(Box<CompletionLine>),
+    /// A positional argument, with the values it takes and their descriptions
+    Argument(Vec<(String, Option<String>)>),
+}
+
+/// What's been parsed so far.
+struct Parser<'a> {
+    /// The command the script completes
+    command: &'a str,
+    /// Every completion which has been added, to skip redefinitions
+    added: Vec<CompletionLine>,
+    completions: Completions,
+}
+
+impl<'a> Parser<'a> {
+    fn new(command: &'a str) -> Self {
+        Self {
+            command,
+            added: vec![],
+            completions: Completions::default(),
+        }
+    }
+
+    /// The (sub)command which the current command completes, if any. Within
+    /// functions other than the main one and those for subcommands, this is
+    /// `None`, since they complete something else.
+    fn context(&self, scope: &Scope) -> Option<String> {
+        let mut context = self.command.to_string();
+        for frame in &scope.frames {
+            match frame {
+                Frame::Function { name, .. } => {
+                    let name = name.trim_start_matches('_');
+                    if name == self.command {
+                        context = self.command.to_string();
+                    } else if let Some(sub) = name
+                        .strip_prefix(self.command)
+                        .and_then(|it| it.strip_prefix('-'))
+                    {
+                        context = format!("{} {sub}", self.command);
+                    } else {
+                        return None;
+                    }
+                }
+                Frame::Case {
+                    subject,
+                    pattern: Some(pattern),
+                } if subject.contains("words[1]") || subject.contains("line[1]") => {
+                    let label = pattern.split('|').next().unwrap_or(pattern);
+                    if regex_is_match!(r"^[[:alnum:]][[:alnum:]_.-]*$", label) {
+                        context.push(' ');
+                        context.push_str(label);
+                    }
+                }
+                Frame::Case { .. } => {}
+            }
+        }
+        Some(context)
+    }
+
+    /// Add a completion, unless it redefines an option which has already been
+    /// added.
+    fn add(&mut self, completion: CompletionLine) -> Result<()> {
+        if self
+            .added
+            .iter()
+            .any(|it| it.command == completion.command && it.overlaps(&completion))
+        {
+            trace!(completion = completion.command; "skipping option which was already defined");
+            return Ok(());
+        }
+        self.added.push(completion.clone());
+        self.completions.add(completion)
+    }
+
+    /// Add the completions for the specs passed to `_arguments`.
+    fn arguments(&mut self, scope: &Scope, words: &[Vec<String>]) -> Result<()> {
+        let Some(command) = self.context(scope) else {
+            trace!("skipping _arguments outside of a command's completions");
+            return Ok(());
+        };
+        let mut specs = vec![];
+        let mut words = words.iter();
+        while let Some(word) = words.next() {
+            let text = word[0].as_str();
+            if specs.is_empty() && regex_is_match!(r"^-[nswWCRS]+$|^:$", text) {
+                // options to _arguments itself
+            } else if (specs.is_empty() && ["-A", "-O", "-M"].contains(&text)) || text == "+" {
+                // options to _arguments with a value, or the name of a set
+                // of specs
+                words.next();
+            } else if let Some((_, name)) =
+                regex_captures!(r"^\$\{?(?:\(@\))?(\w+)(?:\[[@*]\])?\}?$", text)
+            {
+                specs.extend(scope.arrays.get(name).cloned().unwrap_or_default());
+            } else {
+                specs.push(word.clone());
+            }
+        }
+        for spec in specs {
+            // The alternatives of a brace expansion (like `{-v,--verbose}`)
+            // are names for the same option.
+            let mut option: Option<CompletionLine> = None;
+            for alternative in &spec {
+                match parse_spec(alternative) {
+                    Some(Spec::Option(parsed)) => match &mut option {
+                        Some(option) => {
+                            option.short.extend(parsed.short);
+                            option.long.extend(parsed.long);
+                            option.old_option.extend(parsed.old_option);
+                        }
+                        None => option = Some(*parsed),
+                    },
+                    Some(Spec::Argument(values)) => {
+                        for (value, description) in values {
+                            self.add(CompletionLine {
+                                command: Some(command.clone()),
+                                argument: Some(shell_words::quote(&value).into_owned()),
+                                description,
+                                ..Default::default()
+                            })?;
+                        }
+                    }
+                    None => trace!(spec = alternative; "skipping spec"),
+                }
+            }
+            if let Some(mut option) = option {
+                option.command = Some(command.clone());
+                self.add(option)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the subcommands listed by `_describe`, if it's describing
+    /// commands.
+    fn describe(&mut self, scope: &Scope, words: &[Vec<String>]) -> Result<()> {
+        let Some(command) = self.context(scope) else {
+            return Ok(());
+        };
+        if !words.iter().any(|it| it[0].contains("command")) {
+            trace!("skipping _describe which doesn't describe commands");
+            return Ok(());
+        }
+        // The tag (after `-t`) is often named like the array.
+        let mut names: Vec<_> = words
+            .iter()
+            .map(|it| it[0].trim_start_matches('$'))
+            .collect();
+        let mut seen = HashSet::new();
+        names.retain(|name| seen.insert(*name));
+        let elements: Vec<String> = names
+            .into_iter()
+            .filter_map(|name| scope.arrays.get(name))
+            .flatten()
+            .flatten()
+            .cloned()
+            .collect();
+        for element in elements {
+            let (name, description) = match split_unescaped(&element) {
+                Some((name, description)) => (unescape(name), Some(unescape(description))),
+                None => (unescape(&element), None),
+            };
+            self.add(CompletionLine {
+                command: Some(command.clone()),
+                condition: Some(USE_SUBCOMMAND.to_string()),
+                argument: Some(name),
+                description,
+                no_files: true,
+                ..Default::default()
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Split `text` at its first colon which isn't escaped with a backslash.
+fn split_unescaped(text: &str) -> Option<(&str, &str)> {
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '\\' => escaped = !escaped,
+            ':' if !escaped => return Some((&text[..i], &text[i + 1..])),
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+/// Remove the backslashes escaping characters in `text`.
+fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        result.extend(if c == '\\' { chars.next() } else { Some(c) });
+    }
+    result
+}
+
+/// The values listed by a value action, like `(a b c)`, or `((a\:A b\:B))`
+/// which also describes them.
+fn values(action: &str) -> Vec<(String, Option<String>)> {
+    let action = action.trim();
+    let (inner, described) = if let Some(inner) = action
+        .strip_prefix("((")
+        .and_then(|it| it.strip_suffix("))"))
+    {
+        (inner, true)
+    } else if let Some(inner) = action.strip_prefix('(').and_then(|it| it.strip_suffix(')')) {
+        (inner, false)
+    } else {
+        return vec![];
+    };
+    let Ok(items) = shell_words::split(&inner.replace("\\:", ":")) else {
+        return vec![];
+    };
+    items
+        .into_iter()
+        .map(|item| match item.split_once(':') {
+            Some((value, description)) if described => {
+                (value.to_string(), Some(description.to_string()))
+            }
+            _ => (item, None),
+        })
+        .collect()
+}
+
+/// Parse a spec passed to `_arguments`, like `(-v --verbose)-v[be verbose]`
+/// or `1:file:_files`. Specs which can't be completed by nu are `None`.
+fn parse_spec(spec: &str) -> Option<Spec> {
+    let (exclusions, spec) = match spec.strip_prefix('(') {
+        Some(rest) => {
+            let end = rest.find(')')?;
+            let exclusions = rest[..end].split_whitespace().map(str::to_string).collect();
+            (exclusions, &rest[end + 1..])
+        }
+        None => (vec![], spec),
+    };
+    let spec = spec.trim_start_matches('*');
+    if !spec.starts_with('-') {
+        // A positional argument, like `1:message:action` or `*:message:action`
+        let value = spec
+            .trim_start_matches(|c: char| c.is_ascii_digit())
+            .strip_prefix(':')?
+            .trim_start_matches(':');
+        let (_, action) = split_unescaped(value)?;
+        let values = values(&action.replace("\\:", ":"));
+        return (!values.is_empty()).then_some(Spec::Argument(values));
+    }
+    let end = spec.find(['[', ':']).unwrap_or(spec.len());
+    let (name, mut rest) = spec.split_at(end);
+    let mut description = None;
+    if let Some(inner) = rest.strip_prefix('[') {
+        let mut escaped = false;
+        let close = inner.char_indices().find_map(|(i, c)| match c {
+            '\\' => {
+                escaped = !escaped;
+                None
+            }
+            ']' if !escaped => Some(i),
+            _ => {
+                escaped = false;
+                None
+            }
+        })?;
+        description = Some(unescape(&inner[..close]));
+        rest = &inner[close + 1..];
+    }
+    let value = rest.strip_prefix(':');
+    // The suffixes of the name say how the value is given: after an `=`, in
+    // the same word (`-`) or either in the same word or the next (`+`).
+    let mut name = name
+        .strip_suffix("=-")
+        .or_else(|| name.strip_suffix(['=', '+']))
+        .unwrap_or(name);
+    if value.is_some() && name.len() > 2 {
+        name = name.strip_suffix('-').unwrap_or(name);
+    }
+    let mut option = parse_option_spec(name)?;
+    option.description = description;
+    option.exclusions = exclusions;
+    if let Some(value) = value {
+        let optional = value.starts_with(':');
+        option.require_parameter = !optional;
+        if let Some((_, action)) = split_unescaped(value.trim_start_matches(':')) {
+            let values: Vec<_> = values(&action.replace("\\:", ":"))
+                .into_iter()
+                .map(|(value, _)| value)
+                .collect();
+            if !values.is_empty() {
+                option.argument = Some(shell_words::join(values));
+                option.no_files = true;
+            }
+        }
+    }
+    Some(Spec::Option(Box::new(option)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = r#"#compdef mockery
+
+_mockery-run() {
+  _arguments \
+    '--now[run immediately]' \
+    '*:target:((fast\:quickly slow\:carefully))'
+}
+
+local -a commands common
+commands=(
+  'run:Run something'
+  'build:Build\: something'  # a comment
+)
+common=( '(-q --quiet)'{-q,--quiet}'[be quiet]' )
+
+_arguments -s -S -C \
+  $common \
+  '(-v --verbose)'{-v,--verbose}'[be verbose \[very\]]' \
+  '--color=[when to color]:when:(always never auto)' \
+  '-o+[output file]:file:_files' \
+  '(- *)--help[show help]' \
+  '1: :->command' \
+  '*::arg:->args' && ret=0
+
+case $state in
+  command)
+    _describe -t commands 'mockery command' commands ;;
+  args)
+    case $words[1] in
+      (build)
+        _arguments '--release[build optimised]' ;;
+    esac
+    ;;
+esac
+"#;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let completions = parse("mockery", SCRIPT)?;
+        let completions = completions.read().expect("rwlock read access");
+        let options = &completions[String::from("mockery")];
+        assert_eq!(options.len(), 7);
+        assert_eq!(options[0].short, vec!["q"]);
+        assert_eq!(options[0].long, vec!["quiet"]);
+        assert_eq!(options[0].exclusions, vec!["-q", "--quiet"]);
+        assert_eq!(options[1].short, vec!["v"]);
+        assert_eq!(options[1].long, vec!["verbose"]);
+        assert_eq!(options[1].description.as_deref(), Some("be verbose [very]"));
+        assert!(!options[1].takes_value());
+        assert_eq!(options[2].long, vec!["color"]);
+        assert_eq!(options[2].values(), vec!["always", "never", "auto"]);
+        assert_eq!(options[3].short, vec!["o"]);
+        assert!(options[3].takes_value());
+        assert!(options[3].values().is_empty());
+        assert_eq!(options[4].exclusions, vec!["-", "*"]);
+        assert_eq!(options[5].argument.as_deref(), Some("run"));
+        assert_eq!(options[5].condition.as_deref(), Some(USE_SUBCOMMAND));
+        assert_eq!(options[6].argument.as_deref(), Some("build"));
+        assert_eq!(options[6].description.as_deref(), Some("Build: something"));
+
+        let run = &completions[String::from("mockery run")];
+        assert_eq!(run.len(), 3);
+        assert_eq!(run[0].long, vec!["now"]);
+        assert_eq!(run[1].argument.as_deref(), Some("fast"));
+        assert_eq!(run[1].description.as_deref(), Some("quickly"));
+        let build = &completions[String::from("mockery build")];
+        assert_eq!(build[0].long, vec!["release"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_helpers_define_nothing() -> Result<()> {
+        let completions = parse("files", "#autoload\n_arguments '--all[everything]'\n")?;
+        assert!(completions.read().expect("rwlock read access").is_empty());
+        Ok(())
+    }
+}
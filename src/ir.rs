@@ -0,0 +1,371 @@
+//! A JSON representation of the parsed completions as a tree of commands,
+//! which `dump-ir` prints and `--from-ir` reads, so that other tools can
+//! consume the completions this parses, or write their own for it to convert.
+//!
+//! A document looks like:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "commands": [
+//!     {
+//!       "name": "git",
+//!       "flags": [
+//!         {
+//!           "short": ["C"],
+//!           "description": "Run as if git was started in the given path",
+//!           "value": { "type": "directory" }
+//!         }
+//!       ],
+//!       "subcommands": [
+//!         {
+//!           "name": "remote",
+//!           "description": "Manage tracked repositories",
+//!           "positionals": [{ "value": "add", "description": "Add a remote" }]
+//!         }
+//!       ]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! - `version` is [`VERSION`], which changes whenever the meaning of a
+//!   document does.
+//! - Each command has a `name`, and the `description` shown when it's
+//!   offered as a subcommand, the `flags` it takes, the `positionals` (the
+//!   candidates for its positional arguments) and its `subcommands`, which are
+//!   commands too.
+//! - Each flag has its `short` (`-v`), `long` (`--verbose`) and `old_style`
+//!   (`-verbose`) names without their dashes, a `description`, the
+//!   `exclusions` (the flags which can't be given after it, as they're typed,
+//!   where `-` means every flag) and, if it takes one, the `value`.
+//! - A value has the nu `type` of it (`string` unless it's known to be more
+//!   specific, like `path`) and its `candidates`.
+//! - Each candidate has a `value` and a `description`.
+//!
+//! Every field but the names and values is optional.
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    completion_line::CompletionLine,
+    completions::Completions,
+    config::{Config, DumpIrOptions},
+    input::USE_SUBCOMMAND,
+};
+
+/// The version of the format, which must be incremented whenever the
+/// meaning of a document changes.
+pub(crate) const VERSION: u32 = 1;
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Document {
+    pub(crate) version: u32,
+    #[serde(default)]
+    pub(crate) commands: Vec<Command>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Command {
+    pub(crate) name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) flags: Vec<Flag>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) positionals: Vec<Candidate>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) subcommands: Vec<Command>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Flag {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) short: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) long: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) old_style: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) exclusions: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) value: Option<Value>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Value {
+    #[serde(default = "string", rename = "type")]
+    pub(crate) value_type: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) candidates: Vec<Candidate>,
+}
+
+fn string() -> String {
+    "string".to_string()
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Candidate {
+    pub(crate) value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) description: Option<String>,
+}
+
+impl Candidate {
+    /// This candidate as it's listed in the argument of a completion, with
+    /// its description after a tab (see [`CompletionLine::candidates`]).
+    fn word(&self) -> String {
+        match &self.description {
+            Some(description) => format!("{}\t{description}", self.value),
+            None => self.value.clone(),
+        }
+    }
+}
+
+impl Document {
+    /// The tree of the commands in `completions`.
+    pub(crate) fn from_completions(completions: &Completions) -> Self {
+        let mut root = Command::default();
+        for (name, lines) in completions.read().expect("rwlock read access").iter() {
+            let command = name
+                .split(' ')
+                .fold(&mut root, |command, name| command.subcommand(name));
+            for line in lines {
+                command.add(line);
+            }
+        }
+        Self {
+            version: VERSION,
+            commands: root.subcommands,
+        }
+    }
+
+    /// The completions for the commands in this document.
+    pub(crate) fn into_completions(self) -> Result<Completions> {
+        if self.version != VERSION {
+            return Err(anyhow!(
+                "unsupported IR version {}, expected {VERSION}",
+                self.version
+            ));
+        }
+        let completions = Completions::default();
+        for command in self.commands {
+            command.add_to(&completions, None)?;
+        }
+        Ok(completions)
+    }
+}
+
+impl Command {
+    /// The subcommand `name` of this command, which is added if it isn't
+    /// already there.
+    fn subcommand(&mut self, name: &str) -> &mut Command {
+        let index = match self.subcommands.iter().position(|it| it.name == name) {
+            Some(index) => index,
+            None => {
+                self.subcommands.push(Command {
+                    name: name.to_string(),
+                    ..Command::default()
+                });
+                self.subcommands.len() - 1
+            }
+        };
+        &mut self.subcommands[index]
+    }
+
+    /// Add a completion for this command.
+    fn add(&mut self, line: &CompletionLine) {
+        if !line.short.is_empty() || !line.long.is_empty() || !line.old_option.is_empty() {
+            self.flags.push(Flag {
+                short: line.short.clone(),
+                long: line.long.clone(),
+                old_style: line.old_option.clone(),
+                description: line.description.clone(),
+                exclusions: line.exclusions.clone(),
+                value: line.takes_value().then(|| Value {
+                    value_type: line.value_type.clone().unwrap_or_else(string),
+                    candidates: line
+                        .candidates()
+                        .into_iter()
+                        .map(|(value, description)| Candidate { value, description })
+                        .collect(),
+                }),
+            });
+        } else if line.condition.as_deref() == Some(USE_SUBCOMMAND) {
+            for value in line.values() {
+                let subcommand = self.subcommand(&value);
+                if subcommand.description.is_none() {
+                    subcommand.description = line.description.clone();
+                }
+            }
+        } else {
+            self.positionals
+                .extend(
+                    line.candidates()
+                        .into_iter()
+                        .map(|(value, description)| Candidate {
+                            value,
+                            description: description.or_else(|| line.description.clone()),
+                        }),
+                );
+        }
+    }
+
+    /// Add the completions for this command, and its subcommands, which is a
+    /// subcommand of `parent` if there is one.
+    fn add_to(self, completions: &Completions, parent: Option<&str>) -> Result<()> {
+        let name = match parent {
+            Some(parent) => format!("{parent} {}", self.name),
+            None => self.name,
+        };
+        for flag in self.flags {
+            let candidates = flag.value.as_ref().map(|it| &it.candidates);
+            completions.add(CompletionLine {
+                short: flag.short,
+                long: flag.long,
+                old_option: flag.old_style,
+                command: Some(name.clone()),
+                description: flag.description,
+                exclusions: flag.exclusions,
+                require_parameter: flag.value.is_some(),
+                argument: candidates
+                    .filter(|it| !it.is_empty())
+                    .map(|it| shell_words::join(it.iter().map(Candidate::word))),
+                no_files: candidates.map_or(false, |it| !it.is_empty()),
+                value_type: flag
+                    .value
+                    .map(|it| it.value_type)
+                    .filter(|it| it != "string"),
+                ..CompletionLine::default()
+            })?;
+        }
+        for candidate in self.positionals {
+            completions.add(CompletionLine {
+                command: Some(name.clone()),
+                argument: Some(shell_words::quote(&candidate.value).into_owned()),
+                description: candidate.description,
+                no_files: true,
+                ..CompletionLine::default()
+            })?;
+        }
+        for subcommand in self.subcommands {
+            completions.add(CompletionLine {
+                command: Some(name.clone()),
+                argument: Some(shell_words::quote(&subcommand.name).into_owned()),
+                description: subcommand.description.clone(),
+                condition: Some(USE_SUBCOMMAND.to_string()),
+                no_files: true,
+                ..CompletionLine::default()
+            })?;
+            subcommand.add_to(completions, Some(&name))?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse the completions in the document at `path`.
+pub(crate) fn parse_file(path: &Path) -> Result<Completions> {
+    let document: Document = serde_json::from_slice(&fs::read(path)?)
+        .map_err(|err| anyhow!("parsing IR document {path:?}: {err}"))?;
+    document.into_completions()
+}
+
+/// Print the completions parsed from the sources in `opts` as a document.
+pub(crate) fn dump(opts: &DumpIrOptions) -> Result<()> {
+    let completions = Completions::default();
+    for source in Config::sources_or_discovered(&opts.sources) {
+        for path in Config::input_format().source_files(Path::new(source))? {
+            match Config::input_format().parse(&path) {
+                Ok(parsed) => completions.merge(&parsed),
+                Err(err) if !Config::fail_fast() => {
+                    warn!(file = path.to_string_lossy(), error = err.to_string(); "failed to parse source");
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    completions.resolve_wraps(&mut |_| Ok(None))?;
+    completions.remove_internal_commands();
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&Document::from_completions(&completions))?
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let document: Document = serde_json::from_str(
+            r#"{
+                "version": 1,
+                "commands": [{
+                    "name": "mockery",
+                    "flags": [
+                        { "short": ["v"], "long": ["verbose"], "exclusions": ["-q"] },
+                        { "long": ["out"], "value": { "type": "path" } },
+                        {
+                            "long": ["color"],
+                            "value": {
+                                "candidates": [
+                                    { "value": "always", "description": "Always colour" },
+                                    { "value": "never" }
+                                ]
+                            }
+                        }
+                    ],
+                    "positionals": [{ "value": "file one", "description": "The first" }],
+                    "subcommands": [{
+                        "name": "build",
+                        "description": "Build it",
+                        "subcommands": [{ "name": "all" }]
+                    }]
+                }]
+            }"#,
+        )?;
+        let completions = document.into_completions()?;
+        {
+            let lines = completions.read().expect("rwlock read access");
+            let root = &lines[String::from("mockery")];
+            assert_eq!(root[1].value_type.as_deref(), Some("path"));
+            assert!(root[1].takes_value());
+            assert_eq!(root[2].values(), vec!["always", "never"]);
+            assert_eq!(root[3].values(), vec!["file one"]);
+            assert_eq!(root[4].condition.as_deref(), Some(USE_SUBCOMMAND));
+            assert_eq!(
+                lines[String::from("mockery build")][0].argument.as_deref(),
+                Some("all")
+            );
+        }
+        let document = Document::from_completions(&completions);
+        let root = &document.commands[0];
+        assert_eq!(root.flags[0].exclusions, vec!["-q"]);
+        assert_eq!(root.flags[0].value, None);
+        assert_eq!(root.flags[1].value.as_ref().unwrap().value_type, "path");
+        let candidates = &root.flags[2].value.as_ref().unwrap().candidates;
+        assert_eq!(candidates.len(), 2);
+        assert_eq!(candidates[0].description.as_deref(), Some("Always colour"));
+        assert_eq!(candidates[1].description, None);
+        assert_eq!(
+            root.positionals[0].description.as_deref(),
+            Some("The first")
+        );
+        assert_eq!(root.subcommands[0].description.as_deref(), Some("Build it"));
+        assert_eq!(root.subcommands[0].subcommands[0].name, "all");
+        assert!(Document {
+            version: VERSION + 1,
+            commands: vec![]
+        }
+        .into_completions()
+        .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+//! Run work on a bounded number of threads.
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use anyhow::Result;
+use log::trace;
+
+/// Call `f` on each of `items`, using at most `jobs` threads. The results are
+/// returned in the same order as `items`, regardless of the order the work
+/// finished in.
+///
+/// If `fail_fast` is true, no more work is started once any call has failed,
+/// and the result for each item which wasn't started is `None`.
+pub(crate) fn run_bounded<T, R, F>(
+    items: &[T],
+    jobs: usize,
+    fail_fast: bool,
+    f: F,
+) -> Vec<Option<Result<R>>>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> Result<R> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results: Mutex<Vec<Option<Result<R>>>> = Mutex::new(items.iter().map(|_| None).collect());
+    thread::scope(|scope| {
+        for job in 0..jobs.clamp(1, items.len().max(1)) {
+            let (next, failed, results, f) = (&next, &failed, &results, &f);
+            scope.spawn(move || loop {
+                if fail_fast && failed.load(Ordering::Acquire) {
+                    trace!(job = job; "stopping after failure");
+                    break;
+                }
+                let n = next.fetch_add(1, Ordering::AcqRel);
+                let Some(item) = items.get(n) else {
+                    break;
+                };
+                let result = f(item);
+                if result.is_err() {
+                    failed.store(true, Ordering::Release);
+                }
+                results.lock().expect("poisoned mutex")[n] = Some(result);
+            });
+        }
+    });
+    results.into_inner().expect("poisoned mutex")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_run_bounded_preserves_order() {
+        let items: Vec<usize> = (0..100).collect();
+        let results = run_bounded(&items, 8, false, |n| {
+            if n % 10 == 3 {
+                Err(anyhow!("{n}"))
+            } else {
+                Ok(n * 2)
+            }
+        });
+        assert_eq!(results.len(), 100);
+        for (n, result) in results.into_iter().enumerate() {
+            match result.expect("every item to run") {
+                Ok(doubled) => assert_eq!(doubled, n * 2),
+                Err(err) => assert_eq!(err.to_string(), n.to_string()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_bounded_fail_fast() {
+        let items: Vec<usize> = (0..100).collect();
+        let results = run_bounded(&items, 1, true, |n| {
+            if *n == 3 {
+                Err(anyhow!("failed"))
+            } else {
+                Ok(())
+            }
+        });
+        assert!(results[..3].iter().all(|it| matches!(it, Some(Ok(())))));
+        assert!(matches!(results[3], Some(Err(_))));
+        assert!(results[4..].iter().all(Option::is_none));
+    }
+}
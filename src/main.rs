@@ -1,74 +1,155 @@
 #![feature(once_cell, never_type, exit_status_error, async_closure, let_chains)]
+mod complete;
 mod completion_line;
 mod completions;
 mod config;
 mod dir_walker;
+mod history;
+mod index;
+mod input;
+mod ir;
+mod jobs;
+mod manifest;
 mod nu;
 mod patching;
+mod watch;
 use beau_collector::BeauCollector as _;
 use patching::fetch_latest_patch_set;
 
 use std::{
-    fs::{create_dir, File},
+    fs::{create_dir, create_dir_all, remove_file, File},
     io::{BufRead, BufReader, Seek, Write},
-    path::{Path, PathBuf},
+    os::unix::fs::symlink,
+    path::Path,
     sync::LazyLock,
 };
 
-use config::Config;
-use log::{debug, info, trace};
+use anyhow::anyhow;
+use config::{BuildIndexOptions, Config, OutputMode};
+use log::{as_debug, debug, info, trace, warn};
 
-use crate::nu::{processing_failed, CompletionsProcessor};
+use crate::{
+    history::CommandUsage,
+    manifest::Manifest,
+    nu::{processing_failed, CompletionsProcessor},
+};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     femme::with_level(Config::verbose().log_level_filter());
-    let mut conversion_errors: Vec<Result<_, _>> = vec![];
 
-    if let Some(options) = Config::generate_patches() {
+    if let Some(options) = Config::complete() {
+        complete::complete(options)?;
+    } else if let Some(options) = Config::lookup() {
+        index::lookup(options)?;
+    } else if let Some(options) = Config::stats() {
+        index::print_stats(options)?;
+    } else if let Some(options) = Config::dump_ir() {
+        ir::dump(options)?;
+    } else if let Some(options) = Config::build_index() {
+        build_index(options)?;
+    } else if let Some(options) = Config::generate_patches() {
         patching::generate_patches(options)?;
     } else if let Some(install_location) = Config::install() {
         install_config(install_location)?;
+    } else if let Some(autoload_dir) = Config::autoload() {
+        install_autoload(&autoload_dir)?;
+    } else if let Some(options) = Config::watch() {
+        if Config::update_patch_set() {
+            fetch_latest_patch_set().await?;
+        }
+        watch::watch(options)?;
     } else {
         if Config::update_patch_set() {
             fetch_latest_patch_set().await?;
         }
-        if Config::convert() {
-            if !Config::output_dir().exists() {
-                trace!(
-                    "output directory '{:?}' does not exist, creating",
-                    Config::output_dir()
-                );
-                create_dir(Config::output_dir())?;
-                debug!("created output directory {:?}", Config::output_dir());
-            }
+        generate(Config::sources())?;
+    }
+    Ok(())
+}
 
-            let processor = CompletionsProcessor::default();
-            info!("beginning translation phase");
-            for source in Config::sources() {
-                let path: PathBuf = source.into();
-                if let Err(err) = processor.process_file_or_dir(path) {
-                    let result = processing_failed(source, err).map(|_| unreachable!());
-                    if Config::fail_fast() {
-                        trace!("failing fast");
-                        return result;
-                    } else {
-                        trace!("deferring failure");
-                        conversion_errors.push(result);
-                    }
-                }
+/// Convert every file in `sources`, apply their patches, and write the
+/// imports file, according to the [`Config`].
+pub(crate) fn generate(sources: &[impl AsRef<Path>]) -> anyhow::Result<()> {
+    let mut conversion_errors: Vec<Result<_, _>> = vec![];
+    let mut processor =
+        CompletionsProcessor::default().with_wrapper_aliases(Config::wrapper_aliases());
+    if Config::min_uses().is_some() {
+        match CommandUsage::load(&Config::history_files())? {
+            Some(usage) => processor = processor.with_usage(usage),
+            None => warn!("no history could be read, so --min-uses is ignored"),
+        }
+    }
+    if Config::convert() {
+        processor = processor.with_manifest(Manifest::load(
+            Config::output_dir(),
+            Config::manifest_options(),
+        ));
+        if !Config::output_dir().exists() {
+            trace!(
+                "output directory '{:?}' does not exist, creating",
+                Config::output_dir()
+            );
+            create_dir(Config::output_dir())?;
+            debug!("created output directory {:?}", Config::output_dir());
+        }
+
+        info!("beginning translation phase");
+        for (path, err) in processor.process_sources(sources, Config::output_dir()) {
+            let result = processing_failed(path, err).map(|_| unreachable!());
+            if Config::fail_fast() {
+                trace!("failing fast");
+                return result;
+            } else {
+                trace!("deferring failure");
+                conversion_errors.push(result);
             }
-            debug!(error_count = conversion_errors.len(); "finished processing all translations");
-            processor.write_sourcing_file(&Config::imports_location())?;
-            info!("finished translation phase");
         }
-        if Config::patch() {
-            info!("beginning patch phase");
-            patching::patch_all()?;
-            info!("finished patching");
+        debug!(error_count = conversion_errors.len(); "finished processing all translations");
+        processor.remove_stale_definitions()?;
+        info!("finished translation phase");
+    }
+    // There are no definitions to patch in lazy mode.
+    if Config::patch() && Config::output_mode() != OutputMode::Lazy {
+        info!("beginning patch phase");
+        patching::patch_all(sources, &processor.unchanged_definitions())?;
+        info!("finished patching");
+    }
+    if Config::convert() {
+        // The imports are written after patching so that a bundle
+        // contains the patched definitions.
+        match Config::output_mode() {
+            OutputMode::Sourced => processor.write_sourcing_file(&Config::imports_location())?,
+            OutputMode::Module => {
+                processor.write_module(Config::output_dir(), &Config::imports_location())?
+            }
+            OutputMode::Bundle => {
+                let stats = processor.write_bundle(&Config::imports_location())?;
+                println!("{stats}");
+            }
+            OutputMode::Lazy => {
+                processor.write_completer(&Config::index_location(), &Config::imports_location())?
+            }
+        }
+        processor.save_manifest(Config::output_dir())?;
+    }
+    conversion_errors.into_iter().bcollect::<Vec<()>>()?;
+    Ok(())
+}
+
+/// Parse the sources in `opts` and write their completions to an index.
+fn build_index(opts: &BuildIndexOptions) -> anyhow::Result<()> {
+    let processor = CompletionsProcessor::default();
+    let mut errors: Vec<anyhow::Result<()>> = vec![];
+    for (path, err) in processor.index_sources(Config::sources_or_discovered(&opts.sources)) {
+        let result = processing_failed(path, err).map(|_| unreachable!());
+        if Config::fail_fast() {
+            return result;
         }
-        conversion_errors.into_iter().bcollect::<Vec<()>>()?;
+        errors.push(result);
     }
+    processor.write_index(&opts.index.index)?;
+    errors.into_iter().bcollect::<Vec<()>>()?;
     Ok(())
 }
 
@@ -93,3 +174,23 @@ fn install_config(location: &Path) -> anyhow::Result<()> {
     debug!("config written");
     Ok(())
 }
+
+/// Link the imports file into one of nu's autoload directories, so that nu
+/// picks the definitions up without any changes to its config.
+fn install_autoload(dir: &Path) -> anyhow::Result<()> {
+    let imports = Config::imports_location();
+    if !imports.exists() {
+        return Err(anyhow!(
+            "{imports:?} does not exist, generate the definitions before installing them"
+        ));
+    }
+    create_dir_all(dir)?;
+    let location = dir.join("nu-completions.nu");
+    if location.is_symlink() || location.exists() {
+        debug!(location = as_debug!(location); "replacing existing autoload file");
+        remove_file(&location)?;
+    }
+    symlink(&imports, &location)?;
+    debug!(location = as_debug!(location), imports = as_debug!(imports); "autoload link written");
+    Ok(())
+}
@@ -0,0 +1,290 @@
+//! Track which source each definition was generated from, so that
+//! definitions only need to be regenerated when something has changed.
+use std::{
+    collections::BTreeMap,
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::Result;
+use log::{as_debug, debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{Config, InputFormat},
+    input::help,
+    patching::patch_file_for,
+};
+
+/// The name of the manifest file within the output directory.
+const MANIFEST_FILE: &str = ".manifest.json";
+
+/// Everything which goes into a definition, besides the converter itself.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Fingerprint {
+    /// A hash of the source file's contents
+    source: String,
+    /// A hash of the contents of the patch applied to the definition, if any
+    patch: Option<String>,
+}
+
+impl Fingerprint {
+    /// Hash the source at `path`, and the patch which applies to it if
+    /// patches are being applied. Commands which are run for their help are
+    /// hashed by their size and modification time, rather than their contents.
+    pub(crate) fn of(path: &Path) -> Result<Self> {
+        let patch = Config::patch()
+            .then(|| patch_file_for(path))
+            .flatten()
+            .filter(|patch| patch.exists())
+            .map(|patch| hash_file(&patch))
+            .transpose()?;
+        let executable = (Config::input_format() == InputFormat::Help)
+            .then(|| help::executable(path))
+            .flatten();
+        let source = match executable {
+            Some(executable) => hash_metadata(&executable)?,
+            None => hash_file(path)?,
+        };
+        Ok(Self { source, patch })
+    }
+}
+
+/// A 64-bit FNV-1a hash. Fingerprints are compared against those written by
+/// other builds, so they need a hash whose algorithm is specified, unlike
+/// [`std::collections::hash_map::DefaultHasher`].
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> String {
+        format!("{:016x}", self.0)
+    }
+}
+
+fn hash_metadata(path: &Path) -> Result<String> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+    let mut hasher = Fnv1a::new();
+    hasher.write(path.as_os_str().as_bytes());
+    hasher.write(&metadata.len().to_le_bytes());
+    hasher.write(&modified.as_nanos().to_le_bytes());
+    Ok(hasher.finish())
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut hasher = Fnv1a::new();
+    hasher.write(&fs::read(path)?);
+    Ok(hasher.finish())
+}
+
+/// A definition generated by a previous run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    pub(crate) fingerprint: Fingerprint,
+    pub(crate) definition: PathBuf,
+    /// The fingerprints of the sources of each command wrapped by the
+    /// definition's commands which is defined elsewhere, so that the
+    /// definition is regenerated when those change.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) wrapped: Wrapped,
+}
+
+/// The fingerprints of the sources of each wrapped command, keyed by the
+/// command. A command which isn't defined by any source has none.
+pub(crate) type Wrapped = BTreeMap<String, BTreeMap<PathBuf, Fingerprint>>;
+
+/// The definitions generated into an output directory, keyed by the
+/// (canonical) path to the source each was generated from.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    /// The version of the converter which generated the definitions. When
+    /// this changes, every definition is regenerated.
+    version: String,
+    /// The options which affect the contents of the definitions (see
+    /// [`Config::manifest_options`]). When these change, every definition
+    /// is regenerated.
+    options: String,
+    pub(crate) entries: BTreeMap<PathBuf, Entry>,
+    /// Whether the manifest was written by another version or with other
+    /// options, in which case its entries only record which definitions are
+    /// owned by the converter.
+    #[serde(skip)]
+    outdated: bool,
+}
+
+impl Manifest {
+    /// An empty manifest for the current version and the given `options`.
+    pub(crate) fn new(options: String) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            options,
+            entries: BTreeMap::new(),
+            outdated: false,
+        }
+    }
+
+    /// The options which affect the contents of the definitions.
+    pub(crate) fn options(&self) -> &str {
+        &self.options
+    }
+
+    /// Read the manifest from `output_dir`. If there isn't one, an empty
+    /// manifest is returned. If it was written by a different version or
+    /// with different `options`, none of its entries are current, so that
+    /// everything is regenerated. Either way, the manifest which is returned
+    /// has the current version and `options`.
+    pub(crate) fn load(output_dir: &Path, options: String) -> Self {
+        let location = output_dir.join(MANIFEST_FILE);
+        let manifest = match fs::read(&location) {
+            Ok(contents) => serde_json::from_slice::<Self>(&contents).unwrap_or_else(|err| {
+                warn!(location = as_debug!(location), error = as_debug!(err); "invalid manifest, ignoring");
+                Self::new(options.clone())
+            }),
+            Err(err) => {
+                debug!(location = as_debug!(location), error = as_debug!(err); "no manifest");
+                Self::new(options.clone())
+            }
+        };
+        if manifest.version != env!("CARGO_PKG_VERSION") {
+            info!(version = manifest.version; "manifest is from another version, regenerating everything");
+            Self {
+                entries: manifest.entries,
+                outdated: true,
+                ..Self::new(options)
+            }
+        } else if manifest.options != options {
+            info!(options = manifest.options; "manifest was written with other options, regenerating everything");
+            Self {
+                entries: manifest.entries,
+                outdated: true,
+                ..Self::new(options)
+            }
+        } else {
+            manifest
+        }
+    }
+
+    /// Write the manifest into `output_dir`.
+    pub(crate) fn save(&self, output_dir: &Path) -> Result<()> {
+        let location = output_dir.join(MANIFEST_FILE);
+        fs::write(&location, serde_json::to_vec_pretty(self)?)?;
+        debug!(location = as_debug!(location), entries = self.entries.len(); "manifest written");
+        Ok(())
+    }
+
+    /// Whether the definition at `definition` was generated from exactly the
+    /// sources with the given `fingerprints` (their contents, and the patch
+    /// which applies to them), and still exists.
+    pub(crate) fn is_current(
+        &self,
+        fingerprints: &[(PathBuf, Fingerprint)],
+        definition: &Path,
+    ) -> bool {
+        let generated_from = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.definition == definition)
+            .count();
+        !self.outdated
+            && definition.exists()
+            && generated_from == fingerprints.len()
+            && fingerprints.iter().all(|(source, fingerprint)| {
+                self.entries.get(source).map_or(false, |entry| {
+                    &entry.fingerprint == fingerprint && entry.definition == definition
+                })
+            })
+    }
+
+    /// The fingerprints of the sources of the commands wrapped by
+    /// `definition` when it was generated.
+    pub(crate) fn wrapped(&self, definition: &Path) -> Wrapped {
+        let mut wrapped = Wrapped::new();
+        for entry in self.entries.values() {
+            if entry.definition == definition {
+                wrapped.extend(entry.wrapped.clone());
+            }
+        }
+        wrapped
+    }
+}
+
+/// The key for `source` (in the given `format`) in the manifest. A command
+/// which is run for its help (like `true`) is keyed by the executable it
+/// resolves to, so that its definition is only stale once that's removed.
+pub(crate) fn manifest_key(source: &Path, format: InputFormat) -> PathBuf {
+    if format == InputFormat::Help
+        && !source.exists()
+        && let Some(executable) = help::executable(source)
+    {
+        return executable;
+    }
+    fs::canonicalize(source).unwrap_or_else(|_| source.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_hash_is_stable() {
+        let mut hasher = Fnv1a::new();
+        assert_eq!(hasher.finish(), "cbf29ce484222325");
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), "af63dc4c8601ec8c");
+    }
+
+    #[test]
+    fn test_manifest_round_trip() -> Result<()> {
+        let dir = tempdir()?;
+        let source = dir.path().join("mockery.fish");
+        let definition = dir.path().join("mockery.nu");
+        fs::write(&source, "complete -c mockery -s a")?;
+        fs::write(&definition, "")?;
+        let fingerprint = Fingerprint {
+            source: hash_file(&source)?,
+            patch: None,
+        };
+        let mut manifest = Manifest::new(String::new());
+        manifest.entries.insert(
+            source.clone(),
+            Entry {
+                fingerprint: fingerprint.clone(),
+                definition: definition.clone(),
+                wrapped: Wrapped::new(),
+            },
+        );
+        manifest.save(dir.path())?;
+        let outdated = Manifest::load(dir.path(), "--min-uses 3".into());
+        let current = [(source.clone(), fingerprint.clone())];
+        assert!(!outdated.is_current(&current, &definition));
+        assert!(outdated.entries.contains_key(&source));
+        let manifest = Manifest::load(dir.path(), String::new());
+        assert!(manifest.is_current(&current, &definition));
+        let vendored = (dir.path().join("vendor/mockery.fish"), fingerprint);
+        assert!(!manifest.is_current(&[current[0].clone(), vendored], &definition));
+
+        fs::write(&source, "complete -c mockery -s b")?;
+        let changed = Fingerprint {
+            source: hash_file(&source)?,
+            patch: None,
+        };
+        assert!(!manifest.is_current(&[(source, changed)], &definition));
+        fs::remove_file(&definition)?;
+        assert!(!manifest.is_current(&current, &definition));
+        Ok(())
+    }
+}
@@ -1,18 +1,27 @@
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    env,
+    ffi::{OsStr, OsString},
     fmt::Display,
-    fs::File,
-    io::BufRead,
-    io::{self, BufReader, Seek, Write},
+    fs::{self, File},
+    io::{self, Seek, Write},
     path::{Path, PathBuf},
     process::{Command, Stdio},
     sync::{LazyLock, RwLock},
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use log::{as_debug, as_serde, debug, error, info, trace, warn};
 
-use crate::{completions, config::Config, dir_walker::walk_dir};
+use crate::{
+    completion_line::CompletionLine,
+    completions::{self, split_shell_words},
+    config::{Config, InputFormat, OutputMode},
+    history::CommandUsage,
+    index::{self, Index},
+    jobs::run_bounded,
+    manifest::{manifest_key, Entry, Fingerprint, Manifest, Wrapped},
+};
 
 /// Log a failure to process a completion and return the error. This is
 /// essentially a convenience function for logging.
@@ -28,68 +37,489 @@ pub(crate) fn processing_failed(path: impl AsRef<Path>, err: anyhow::Error) -> R
 /// the import file.
 #[derive(Debug, Default)]
 pub(crate) struct CompletionsProcessor {
-    definition_files: RwLock<HashSet<PathBuf>>,
+    definition_files: RwLock<BTreeSet<PathBuf>>,
+    usage: Option<CommandUsage>,
+    /// The parsed completions from each file, kept for the index when the
+    /// output mode is [`OutputMode::Lazy`] or when building an index. These
+    /// are kept separately so that the index doesn't depend on the order the
+    /// files were processed in.
+    index: RwLock<BTreeMap<PathBuf, Index>>,
+    /// The manifest written by the previous run, if definitions are being
+    /// generated into an output directory.
+    manifest: Option<Manifest>,
+    /// The manifest entries for the definitions handled by this run.
+    entries: RwLock<BTreeMap<PathBuf, Entry>>,
+    /// The definitions which were left as they were, because their source and
+    /// patch haven't changed since the previous run.
+    unchanged: RwLock<HashSet<PathBuf>>,
+    /// The sources which were processed by this run, but had nothing left to
+    /// generate a definition for (see [`CompletionsProcessor::parse_file`]).
+    emptied: RwLock<HashSet<PathBuf>>,
+    /// The group of sources for each definition (see
+    /// [`group_by_definition`]), keyed by its file name, where the
+    /// completions of the commands wrapped by others are looked up.
+    groups: RwLock<HashMap<OsString, Vec<PathBuf>>>,
+    /// Whether wrappers are defined as aliases of the commands they wrap
+    /// (see [`Config::wrapper_aliases`]).
+    wrapper_aliases: bool,
 }
 
 impl CompletionsProcessor {
-    /// A convenience function for calling
-    /// [`CompletionsProcessor::process_file_or_dir_given_output_dir`] with
-    /// [`Config::output_dir`].
-    pub(crate) fn process_file_or_dir(&self, path: PathBuf) -> Result<()> {
-        self.process_file_or_dir_given_output_dir(path, Config::output_dir())
+    /// A processor which only generates definitions for commands used at
+    /// least [`Config::min_uses`] times according to `usage`.
+    pub(crate) fn with_usage(self, usage: CommandUsage) -> Self {
+        Self {
+            usage: Some(usage),
+            ..self
+        }
+    }
+
+    /// A processor which records the definitions it generates, and compares
+    /// them against `manifest` (written by the previous run) to skip those
+    /// which are unchanged (see [`Config::incremental`]).
+    pub(crate) fn with_manifest(self, manifest: Manifest) -> Self {
+        Self {
+            manifest: Some(manifest),
+            ..self
+        }
+    }
+
+    /// A processor which also defines wrappers as aliases of the commands
+    /// they wrap, if `wrapper_aliases` is true.
+    pub(crate) fn with_wrapper_aliases(self, wrapper_aliases: bool) -> Self {
+        Self {
+            wrapper_aliases,
+            ..self
+        }
     }
 
-    /// Walk the given output directory, calling `[CompletionsProcessor::process_file_given_output_dir`]
-    /// on each file.
-    pub(crate) fn process_file_or_dir_given_output_dir(
+    /// Call `f` on every group of files in each of `sources` which generate
+    /// a definition with the same name (see [`group_by_definition`]), spread
+    /// across [`Config::jobs`] threads. Each failure is returned along with
+    /// the first path in the group which failed, in the order the sources
+    /// were given. If [`Config::fail_fast`] is true, no more groups are
+    /// started after the first failure.
+    fn for_each_source_group(
         &self,
-        path: PathBuf,
-        output_dir: impl AsRef<Path>,
-    ) -> Result<()> {
-        info!(file = path.to_string_lossy(); "processing file or directory");
-        let output_dir = output_dir.as_ref();
-        walk_dir(&path, (), |path, _| {
-            self.process_file_given_output_dir(&path, output_dir)
+        sources: &[impl AsRef<Path>],
+        f: impl Fn(&[PathBuf]) -> Result<()> + Sync,
+    ) -> Vec<(PathBuf, anyhow::Error)> {
+        let mut files = vec![];
+        let mut failures = vec![];
+        for source in sources {
+            let source = source.as_ref();
+            info!(file = source.to_string_lossy(); "processing file or directory");
+            match Config::input_format().source_files(source) {
+                Ok(found) => files.extend(found),
+                Err(err) => {
+                    failures.push((source.to_path_buf(), err));
+                    if Config::fail_fast() {
+                        return failures;
+                    }
+                }
+            }
+        }
+        let groups = group_by_definition(files, Config::input_format());
+        self.groups
+            .write()
+            .expect("rwlock write access")
+            .extend(groups.iter().filter_map(|group| {
+                Some((
+                    Config::input_format().definition_file_name(&group[0])?,
+                    group.clone(),
+                ))
+            }));
+        let results = run_bounded(&groups, Config::jobs(), Config::fail_fast(), |group| {
+            f(group)
+        });
+        failures.extend(
+            groups
+                .into_iter()
+                .zip(results)
+                .filter_map(|(mut group, result)| Some((group.swap_remove(0), result?.err()?))),
+        );
+        failures
+    }
+
+    /// Process every file in each of `sources` (see
+    /// [`CompletionsProcessor::process_files_given_output_dir`]) in parallel,
+    /// returning any failures (see
+    /// [`CompletionsProcessor::for_each_source_group`]). In
+    /// [`OutputMode::Lazy`], the completions are only added to the index
+    /// (see [`CompletionsProcessor::index_sources`]), as the completer looks
+    /// them up there rather than in any definitions.
+    pub(crate) fn process_sources(
+        &self,
+        sources: &[impl AsRef<Path>],
+        output_dir: &Path,
+    ) -> Vec<(PathBuf, anyhow::Error)> {
+        if Config::output_mode() == OutputMode::Lazy {
+            return self.index_sources(sources);
+        }
+        self.for_each_source_group(sources, |files| {
+            self.process_files_given_output_dir(files, output_dir)
                 .map(|_| ())
         })
     }
 
-    /// Parse the completions listed in the given file, and write their
-    /// equivalent nushell definition into `output_dir` (with the file name,
-    /// but the extension `.nu`). Update the `CompletionsProcessor` state with
-    /// the path to the new definition so that it can be sourced later.
-    pub(crate) fn process_file_given_output_dir(
+    /// Add the completions parsed from every file in each of `sources` to
+    /// the index (see [`CompletionsProcessor::write_index`]) in parallel,
+    /// without writing any definitions. Any failures are returned (see
+    /// [`CompletionsProcessor::for_each_source_group`]).
+    pub(crate) fn index_sources(
         &self,
-        path: &Path,
-        output_dir: &Path,
-    ) -> Result<PathBuf> {
+        sources: &[impl AsRef<Path>],
+    ) -> Vec<(PathBuf, anyhow::Error)> {
+        self.for_each_source_group(sources, |files| {
+            if let Some((completions, _)) = self.parse_files(files)? {
+                self.add_to_index(&files[0], &completions);
+            }
+            Ok(())
+        })
+    }
+
+    /// Parse the completions listed in the given file.
+    ///
+    /// If `--only-installed` or `--min-uses` were given, commands which
+    /// aren't installed or aren't used often enough are left out, and if that
+    /// leaves nothing, `None` is returned. A file which defines no
+    /// completions at all is still returned.
+    fn parse_file(&self, path: &Path) -> Result<Option<completions::Completions>> {
         info!(file = path.to_string_lossy(); "processing file");
-        if !path.is_file() {
+        if !path.is_file() && Config::input_format() != InputFormat::Help {
             return Err(io::Error::new(
                 io::ErrorKind::Unsupported,
                 format!("{path:#?} is not a file"),
             )
             .into());
         }
-        let errmsg = format!("reading file {path:#?}");
-        let file = BufReader::new(File::open(path)?);
-        trace!(file = as_debug!(path); "opened file for processing");
-        let completions =
-            completions::Completions::parse(file.lines().map(|line| line.expect(&errmsg)))?;
+        let completions = Config::input_format().parse(path)?;
         trace!("successfully parsed completions for {path:?}");
-        {}
+        let parsed = completions.read().expect("rwlock read access").len();
+        if let Some(search_path) = Config::installed_path() {
+            completions.retain_commands(|cmd| {
+                let installed = is_installed(cmd, &search_path);
+                if !installed {
+                    debug!(command = cmd; "skipping command which isn't installed");
+                }
+                installed
+            });
+        }
+        if let Some(usage) = &self.usage && let Some(min_uses) = Config::min_uses() {
+            completions.retain_commands(|cmd| {
+                if Config::always_include().iter().any(|it| it == cmd) {
+                    return true;
+                }
+                let uses = usage.uses(cmd);
+                if uses < min_uses {
+                    debug!(command = cmd, uses = uses; "skipping command which isn't used often enough");
+                }
+                uses >= min_uses
+            });
+        }
+        if parsed > 0 && completions.read().expect("rwlock read access").is_empty() {
+            info!(file = path.to_string_lossy(); "no commands left to generate, skipping file");
+            return Ok(None);
+        }
+        Ok(Some(completions))
+    }
+
+    /// Parse the completions listed in each of `paths`, which are in order of
+    /// precedence, and merge them together (see
+    /// [`completions::Completions::merge`]). The completions of the commands
+    /// wrapped by others are then added to them (see
+    /// [`completions::Completions::resolve_wraps`]), before nu's internal
+    /// commands are left out. The completions are returned along with the
+    /// names of the wrapped commands which were looked up in other groups of
+    /// sources (see [`CompletionsProcessor::wrapped_completions`]). `None` is
+    /// returned if none of the files had any commands left (see
+    /// [`CompletionsProcessor::parse_file`]), or if they only had nu's
+    /// internal commands.
+    fn parse_files(
+        &self,
+        paths: &[PathBuf],
+    ) -> Result<Option<(completions::Completions, BTreeSet<String>)>> {
+        let mut merged: Option<completions::Completions> = None;
+        for path in paths {
+            let Some(completions) = self.parse_file(path)? else {
+                continue;
+            };
+            match &merged {
+                Some(merged) => {
+                    debug!(file = path.to_string_lossy(); "merging completions with those of higher precedence");
+                    merged.merge(&completions);
+                }
+                None => merged = Some(completions),
+            }
+        }
+        let Some(merged) = merged else {
+            return Ok(None);
+        };
+        let mut wrapped = BTreeSet::new();
+        merged.resolve_wraps(&mut |command| {
+            wrapped.insert(command.to_string());
+            Ok(self.wrapped_completions(command))
+        })?;
+        let parsed = merged.read().expect("rwlock read access").len();
+        merged.remove_internal_commands();
+        if parsed > 0 && merged.read().expect("rwlock read access").is_empty() {
+            info!(file = paths[0].to_string_lossy(); "only nu's internal commands left, skipping file");
+            return Ok(None);
+        }
+        Ok(Some((merged, wrapped)))
+    }
+
+    /// The completions of `command`, which is wrapped by another, parsed from
+    /// the group of sources which generate its definition, if there is one.
+    fn wrapped_completions(&self, command: &str) -> Option<completions::Completions> {
+        let paths = self.wrapped_sources(command)?;
+        let completions = completions::Completions::default();
+        for path in paths {
+            match Config::input_format().parse(&path) {
+                Ok(parsed) => completions.merge(&parsed),
+                Err(err) => {
+                    warn!(file = path.to_string_lossy(), error = err.to_string(); "failed to parse the completions of a wrapped command");
+                }
+            }
+        }
+        Some(completions)
+    }
+
+    /// The group of sources which generate the definition of `command`, if
+    /// there is one.
+    fn wrapped_sources(&self, command: &str) -> Option<Vec<PathBuf>> {
+        let name = OsString::from(format!("{command}.nu"));
+        self.groups
+            .read()
+            .expect("rwlock read access")
+            .get(&name)
+            .cloned()
+    }
+
+    /// The current fingerprints of the sources of each of the `commands`
+    /// wrapped by a definition (see [`CompletionsProcessor::wrapped_sources`]).
+    fn wrapped_fingerprints<'a>(
+        &self,
+        commands: impl IntoIterator<Item = &'a String>,
+    ) -> Result<Wrapped> {
+        commands
+            .into_iter()
+            .map(|command| {
+                let fingerprints = self
+                    .wrapped_sources(command)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|path| {
+                        Ok((
+                            manifest_key(path, Config::input_format()),
+                            Fingerprint::of(path)?,
+                        ))
+                    })
+                    .collect::<Result<_>>()?;
+                Ok((command.clone(), fingerprints))
+            })
+            .collect()
+    }
+
+    /// Add the completions parsed from the file at `path` to the index.
+    fn add_to_index(&self, path: &Path, completions: &completions::Completions) {
+        let index = completions
+            .read()
+            .expect("rwlock read access")
+            .iter()
+            .map(|(cmd, opts)| (cmd.clone(), opts.clone()))
+            .collect();
+        self.index
+            .write()
+            .expect("rwlock write access")
+            .insert(path.to_path_buf(), index);
+    }
+
+    /// Parse the completions listed in the given file, and write their
+    /// equivalent nushell definition into `output_dir` (see
+    /// [`CompletionsProcessor::process_files_given_output_dir`]).
+    pub(crate) fn process_file_given_output_dir(
+        &self,
+        path: &Path,
+        output_dir: &Path,
+    ) -> Result<Option<PathBuf>> {
+        self.process_files_given_output_dir(&[path.to_path_buf()], output_dir)
+    }
+
+    /// Parse the completions listed in the given files, which all have the
+    /// same name and are in order of precedence, and write their merged
+    /// nushell definition into `output_dir` (with the file name, but the
+    /// extension `.nu`). Update the `CompletionsProcessor` state with the
+    /// path to the new definition so that it can be sourced later.
+    ///
+    /// If no commands were left to write (see
+    /// [`CompletionsProcessor::parse_file`]), no definition is written and
+    /// `None` is returned.
+    ///
+    /// If the processor has a manifest, and none of the sources nor their
+    /// patch have changed since the definition was written, nor the sources
+    /// of the commands it wraps, the definition is left as it is.
+    pub(crate) fn process_files_given_output_dir(
+        &self,
+        paths: &[PathBuf],
+        output_dir: &Path,
+    ) -> Result<Option<PathBuf>> {
         let location = output_dir.join(
-            path.with_extension("nu")
-                .file_name()
-                .expect("directory already checked for"),
+            Config::input_format()
+                .definition_file_name(&paths[0])
+                .ok_or_else(|| anyhow!("no definition name for {:?}", paths[0]))?,
         );
-        debug!("writing completions parsed from {path:?} into {location:?}");
-        Completions::at(&location)?.output(completions)?;
+        let fingerprints = match &self.manifest {
+            Some(manifest) => {
+                let fingerprints = paths
+                    .iter()
+                    .map(|path| {
+                        Ok((
+                            manifest_key(path, Config::input_format()),
+                            Fingerprint::of(path)?,
+                        ))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let wrapped = manifest.wrapped(&location);
+                if Config::incremental()
+                    && manifest.is_current(&fingerprints, &location)
+                    && self.wrapped_fingerprints(wrapped.keys())? == wrapped
+                {
+                    debug!(files = as_debug!(paths); "sources unchanged, skipping");
+                    self.unchanged
+                        .write()
+                        .expect("rwlock write access")
+                        .insert(location.clone());
+                    self.record(fingerprints, wrapped, &location);
+                    return Ok(Some(location));
+                }
+                Some(fingerprints)
+            }
+            None => None,
+        };
+        let Some((completions, wrapped)) = self.parse_files(paths)? else {
+            if let Some(fingerprints) = fingerprints {
+                self.emptied
+                    .write()
+                    .expect("rwlock write access")
+                    .extend(fingerprints.into_iter().map(|(key, _)| key));
+            }
+            return Ok(None);
+        };
+        debug!("writing completions parsed from {paths:?} into {location:?}");
+        Completions::at(&location)?
+            .with_wrapper_aliases(self.wrapper_aliases)
+            .output(completions)?;
+        let wrapped = match fingerprints {
+            Some(_) => self.wrapped_fingerprints(&wrapped)?,
+            None => Wrapped::new(),
+        };
+        self.record(fingerprints.unwrap_or_default(), wrapped, &location);
+        Ok(Some(location))
+    }
+
+    /// Note that the definition at `location` was generated from the sources
+    /// with the given `fingerprints`, wrapping the commands whose sources
+    /// have the `wrapped` fingerprints, for the manifest and the imports file.
+    fn record(&self, fingerprints: Vec<(PathBuf, Fingerprint)>, wrapped: Wrapped, location: &Path) {
         self.definition_files
             .write()
             .expect("rwlock write access")
-            .insert(location.clone());
-        Ok(location)
+            .insert(location.to_path_buf());
+        self.entries
+            .write()
+            .expect("rwlock write access")
+            .extend(fingerprints.into_iter().map(|(key, fingerprint)| {
+                (
+                    key,
+                    Entry {
+                        fingerprint,
+                        definition: location.to_path_buf(),
+                        wrapped: wrapped.clone(),
+                    },
+                )
+            }));
+    }
+
+    /// The definitions which were left as they were by this run, and so have
+    /// already been patched.
+    pub(crate) fn unchanged_definitions(&self) -> HashSet<PathBuf> {
+        self.unchanged.read().expect("rwlock read access").clone()
+    }
+
+    /// Whether the definition in a previous run's manifest `entry` for
+    /// `source` is stale: either the source no longer exists, or it has
+    /// nothing left to generate, and no other source generated a definition
+    /// with the same name this run.
+    fn is_stale(&self, source: &Path, entry: &Entry) -> bool {
+        (!source.exists()
+            || self
+                .emptied
+                .read()
+                .expect("rwlock read access")
+                .contains(source))
+            && !self
+                .definition_files
+                .read()
+                .expect("rwlock read access")
+                .contains(&entry.definition)
+    }
+
+    /// Remove the stale definitions (see [`CompletionsProcessor::is_stale`])
+    /// generated by previous runs, unless `--keep-stale` was given. Only
+    /// definitions listed in the manifest are ever removed.
+    pub(crate) fn remove_stale_definitions(&self) -> Result<()> {
+        let Some(manifest) = &self.manifest else {
+            return Ok(());
+        };
+        for (source, entry) in &manifest.entries {
+            if self.is_stale(source, entry) && entry.definition.exists() {
+                if Config::keep_stale() {
+                    debug!(source = source.to_string_lossy(), definition = entry.definition.to_string_lossy(); "keeping stale definition");
+                    continue;
+                }
+                info!(source = source.to_string_lossy(), definition = entry.definition.to_string_lossy(); "removing stale definition");
+                fs::remove_file(&entry.definition)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a manifest of the definitions in `output_dir`, for the next run
+    /// to compare against. Entries from the previous manifest for sources
+    /// which weren't processed this time are kept, unless their definition
+    /// was removed as stale. The manifest is written with the options of the
+    /// previous one, which are those of this run (see [`Manifest::load`]).
+    pub(crate) fn save_manifest(&self, output_dir: &Path) -> Result<()> {
+        let mut manifest = Manifest::new(
+            self.manifest
+                .as_ref()
+                .map_or_else(Config::manifest_options, |it| it.options().to_string()),
+        );
+        if let Some(previous) = &self.manifest {
+            manifest.entries.extend(
+                previous
+                    .entries
+                    .iter()
+                    .filter(|(source, entry)| Config::keep_stale() || !self.is_stale(source, entry))
+                    .map(|(source, entry)| (source.clone(), entry.clone())),
+            );
+        }
+        manifest
+            .entries
+            .extend(self.entries.read().expect("rwlock read access").clone());
+        manifest.save(output_dir)
+    }
+
+    /// The definition files which have been written, in order, so that the
+    /// output doesn't depend on the order the files were processed in.
+    fn sorted_definition_files(&self) -> Vec<PathBuf> {
+        self.definition_files
+            .read()
+            .expect("rwlock read access")
+            .iter()
+            .cloned()
+            .collect()
     }
 
     /// After all the completions have been generated and their filenames,
@@ -97,16 +527,251 @@ impl CompletionsProcessor {
     /// of the definitions and can be sourced in turn.
     pub(crate) fn write_sourcing_file(&self, to: &Path) -> Result<()> {
         let mut file = File::create(to)?;
-        for def in self
-            .definition_files
-            .read()
-            .expect("rwlock read access")
-            .iter()
-        {
+        for def in &self.sorted_definition_files() {
             file.write_all(format!("source {def:?}\n").as_bytes())?;
         }
         Ok(())
     }
+
+    /// Turn `output_dir` into a nu module by writing a `mod.nu` which
+    /// re-exports each of the definitions, then write an imports file to `to`
+    /// which `use`s that module. nu then parses all of the definitions as a
+    /// single module, rather than sourcing each file individually.
+    pub(crate) fn write_module(&self, output_dir: &Path, to: &Path) -> Result<()> {
+        let mut module = File::create(output_dir.join("mod.nu"))?;
+        for def in &self.sorted_definition_files() {
+            let Some(file_name) = def.file_name() else {
+                warn!(definition = as_debug!(def); "definition file has no name, skipping");
+                continue;
+            };
+            module.write_all(format!("export use {file_name:?} *\n").as_bytes())?;
+        }
+        debug!(module = as_debug!(output_dir); "module written");
+        File::create(to)?.write_all(format!("use {output_dir:?} *\n").as_bytes())?;
+        Ok(())
+    }
+
+    /// Write the parsed completions to an index file at `to`.
+    pub(crate) fn write_index(&self, to: &Path) -> Result<()> {
+        let mut index = Index::new();
+        for parsed in self.index.read().expect("rwlock read access").values() {
+            for (cmd, opts) in parsed {
+                index
+                    .entry(cmd.clone())
+                    .or_default()
+                    .extend(opts.iter().cloned());
+            }
+        }
+        index::write_index(&index, to)
+    }
+
+    /// Write the parsed completions to `index`, and an imports file to `to`
+    /// which sets nu's external completer to a closure which looks up
+    /// completions in that index by calling this executable's `complete`
+    /// subcommand. Nothing is parsed when nu starts, so its startup time is
+    /// the same no matter how many completions were converted. Any external
+    /// completer which was already set (like carapace's) is kept, and called
+    /// for the commands which aren't in the index.
+    pub(crate) fn write_completer(&self, index: &Path, to: &Path) -> Result<()> {
+        self.write_index(index)?;
+        let exe = env::current_exe()?;
+        let mut file = File::create(to)?;
+        write!(file, "{}", completer_script(&exe, index))?;
+        Ok(())
+    }
+
+    /// Concatenate all of the definitions into a single file at `to`, under a
+    /// header naming the file each came from. Items which are identical to
+    /// one already written by an earlier file are skipped, and those which
+    /// define the same name differently are reported as conflicts (only the
+    /// first is written, as nu won't define a name twice). This reads the
+    /// definitions back from disk, so it should be called after patching.
+    pub(crate) fn write_bundle(&self, to: &Path) -> Result<BundleStats> {
+        let definition_files = self.sorted_definition_files();
+        let mut stats = BundleStats {
+            location: to.to_path_buf(),
+            ..Default::default()
+        };
+        // The first line of each item written, which names it, along with the
+        // item and the definition it came from.
+        let mut seen: HashMap<String, (String, &PathBuf)> = HashMap::new();
+        let mut file = File::create(to)?;
+        for def in &definition_files {
+            let contents = fs::read_to_string(def)?;
+            let mut header_written = false;
+            for item in split_items(&contents) {
+                let key = item.lines().next().unwrap_or_default().trim().to_string();
+                if let Some((existing, first)) = seen.get(&key) {
+                    if existing == item {
+                        debug!(definition = as_debug!(def), item = item; "skipping duplicate item");
+                        stats.duplicates += 1;
+                    } else {
+                        warn!(
+                            item = key,
+                            kept = first.to_string_lossy(),
+                            skipped = def.to_string_lossy();
+                            "conflicting definitions of the same item, keeping the first"
+                        );
+                        stats.conflicts += 1;
+                    }
+                    continue;
+                }
+                seen.insert(key, (item.to_string(), def));
+                if !header_written {
+                    let name = def.file_stem().unwrap_or_default().to_string_lossy();
+                    file.write_all(format!("\n# ---- {name} ----\n").as_bytes())?;
+                    header_written = true;
+                }
+                file.write_all(item.as_bytes())?;
+                if is_extern(item) {
+                    stats.externs += 1;
+                }
+            }
+            stats.files += 1;
+        }
+        debug!(stats = as_debug!(stats); "bundle written");
+        Ok(stats)
+    }
+}
+
+/// The imports file written in [`OutputMode::Lazy`] (see
+/// [`CompletionsProcessor::write_completer`]), which completes with `exe`
+/// from `index`, falling back to the external completer set before it.
+fn completer_script(exe: &Path, index: &Path) -> String {
+    format!(
+        "let nu_completions_fallback = $env.config.completions.external.completer?\n\
+         $env.config.completions.external.completer = {{|spans|\n    \
+         let candidates = ^{exe:?} complete --index {index:?} ...$spans | from json\n    \
+         if $candidates == null and $nu_completions_fallback != null {{\n        \
+         do $nu_completions_fallback $spans\n    \
+         }} else {{\n        \
+         $candidates\n    \
+         }}\n\
+         }}\n"
+    )
+}
+
+/// Where a fish completion file was found, in the order fish searches for
+/// completions, which is the order in which their completions take
+/// precedence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Precedence {
+    /// Written by the user, e.g. in `~/.config/fish/completions`
+    User,
+    /// Installed with fish or with the command, e.g. in
+    /// `/usr/share/fish/vendor_completions.d`
+    Vendor,
+    /// Generated from man pages by `fish_update_completions`
+    Generated,
+}
+
+impl Precedence {
+    fn of(path: &Path) -> Self {
+        if path
+            .components()
+            .any(|it| it.as_os_str() == "generated_completions")
+        {
+            Self::Generated
+        } else if path.starts_with("/usr/share/fish")
+            || path.starts_with("/usr/local/share/fish")
+            || path
+                .components()
+                .any(|it| it.as_os_str() == "vendor_completions.d")
+        {
+            Self::Vendor
+        } else {
+            Self::User
+        }
+    }
+}
+
+/// Group `files` (in the given `format`) by the name of the definition they
+/// generate, keeping the groups in the order their first file was given.
+/// Within each group, the files are sorted by [`Precedence`], and otherwise in
+/// the order they were given.
+fn group_by_definition(files: Vec<PathBuf>, format: InputFormat) -> Vec<Vec<PathBuf>> {
+    let mut groups: Vec<Vec<PathBuf>> = vec![];
+    let mut by_name = HashMap::new();
+    for file in files {
+        let name = format.definition_file_name(&file);
+        match by_name.get(&name) {
+            Some(&i) => {
+                let group: &mut Vec<_> = &mut groups[i];
+                if !group.contains(&file) {
+                    group.push(file);
+                }
+            }
+            None => {
+                by_name.insert(name, groups.len());
+                groups.push(vec![file]);
+            }
+        }
+    }
+    for group in &mut groups {
+        group.sort_by_key(|file| Precedence::of(file));
+    }
+    groups
+}
+
+/// Whether `cmd` can be found in the given `PATH`. Fish defines the
+/// completions for some subcommands under a dashed name (like `git-add`), so a
+/// command is also considered installed if the part before the first dash is.
+fn is_installed(cmd: &str, search_path: &OsStr) -> bool {
+    let Ok(cwd) = env::current_dir() else {
+        return false;
+    };
+    which::which_in(cmd, Some(search_path), &cwd).is_ok()
+        || cmd.split_once('-').map_or(false, |(parent, _)| {
+            which::which_in(parent, Some(search_path), &cwd).is_ok()
+        })
+}
+
+/// Counts of what went into a bundle written by
+/// [`CompletionsProcessor::write_bundle`].
+#[derive(Debug, Default)]
+pub(crate) struct BundleStats {
+    location: PathBuf,
+    files: usize,
+    externs: usize,
+    duplicates: usize,
+    conflicts: usize,
+}
+
+impl Display for BundleStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "bundled externs={} duplicates={} conflicts={} files={} location={:?}",
+            self.externs, self.duplicates, self.conflicts, self.files, self.location
+        )
+    }
+}
+
+/// Whether the given item (see [`split_items`]) is an extern definition.
+fn is_extern(item: &str) -> bool {
+    item.starts_with("export extern ") || item.starts_with("extern ")
+}
+
+/// Split a nu definitions file into its top-level items (externs, commands,
+/// aliases, etc.) so that they can be deduplicated. Anything which precedes
+/// the first item, such as a comment, is returned as an item of its own. Each
+/// item includes its trailing newline.
+fn split_items(contents: &str) -> Vec<&str> {
+    const ITEM_STARTS: [&str; 6] = ["export ", "extern ", "def ", "module ", "alias ", "use "];
+    let mut items = vec![];
+    let mut start = 0;
+    let mut offset = 0;
+    for line in contents.split_inclusive('\n') {
+        if offset > start && ITEM_STARTS.iter().any(|it| line.starts_with(it)) {
+            items.push(&contents[start..offset]);
+            start = offset;
+        }
+        offset += line.len();
+    }
+    if start < contents.len() {
+        items.push(&contents[start..]);
+    }
+    items
 }
 
 /// A type with the state and methods necessary to write a
@@ -115,12 +780,28 @@ impl CompletionsProcessor {
 pub(crate) struct Completions<IO: Seek + Write> {
     io: IO,
     indent: usize,
+    /// Whether wrappers are also written as aliases of the commands they
+    /// wrap.
+    wrapper_aliases: bool,
 }
 
 impl<IO: Seek + Write> Completions<IO> {
     /// Wrap the given IO with `Completions`.
     fn new(io: IO) -> Self {
-        Self { io, indent: 0 }
+        Self {
+            io,
+            indent: 0,
+            wrapper_aliases: false,
+        }
+    }
+
+    /// Also write wrappers as aliases of the commands they wrap, after their
+    /// extern, if `wrapper_aliases` is true.
+    fn with_wrapper_aliases(self, wrapper_aliases: bool) -> Self {
+        Self {
+            wrapper_aliases,
+            ..self
+        }
     }
 }
 
@@ -160,17 +841,21 @@ impl<IO: Seek + Write> Completions<IO> {
     /// output this set of completions as an extern command.
     pub(crate) fn output(&mut self, completions: completions::Completions) -> Result<()> {
         let mut command_count: usize = 0;
-        for (cmd, opts) in completions.read().expect("rwlock read access").iter() {
+        let commands = by_subcommand(&completions);
+        for (cmd, opts) in &commands {
             let cmd = if let Err(which::Error::CannotCanonicalize) = which::which(cmd) {
                 cmd.replace('-', " ")
             } else {
                 cmd.to_string()
             };
             let cmd = cmd.as_str();
+            let opts = &merge_options(opts);
+            self.write_completers(cmd, opts)?;
             self.write(format!(r#"export extern "{cmd}" ["#))?.eol()?;
             self.indent += 1;
             let mut rules: usize = 0;
             let mut synonyms = vec![];
+            let mut rest = None;
             for option in opts {
                 let (mut def, mut arg) = (String::new(), String::new());
                 match &option.old_option.as_slice() {
@@ -242,11 +927,20 @@ impl<IO: Seek + Write> Completions<IO> {
                     }
                 }
                 if def.is_empty() {
-                    warn!(option = as_debug!(option), cmd = cmd; "no option or arg");
+                    // The type of the positional arguments, if it's known.
+                    if option.argument.is_none() && let Some(value_type) = &option.value_type {
+                        rest.get_or_insert(value_type);
+                    } else if option.wraps.is_empty() {
+                        warn!(option = as_debug!(option), cmd = cmd; "no option or arg");
+                    }
                     continue;
                 }
-                if option.argument.is_some() {
-                    arg.push_str(": string");
+                if let Some(nu_type) = option.nu_type() {
+                    arg.push_str(": ");
+                    arg.push_str(nu_type);
+                    if let Some(completer) = completer_name(cmd, option) {
+                        arg.push_str(&format!("@{completer:?}"));
+                    }
                 }
                 let (def, arg) = (def.as_str(), arg.as_str());
                 debug!(def=def, arg=arg, cmd=cmd; "writing command to file");
@@ -275,15 +969,60 @@ impl<IO: Seek + Write> Completions<IO> {
                 self.write(format!("{name} #  {desc}"))?.eol()?;
                 rules += 1;
             }
+            if let Some(value_type) = rest {
+                self.write(format!("...args: {value_type}"))?.eol()?;
+                rules += 1;
+            }
             debug!(rule_count=rules, cmd=cmd; "wrote rules");
             self.indent -= 1;
             self.write("]\n")?;
             command_count += 1;
+            if self.wrapper_aliases
+                && !cmd.contains(' ')
+                && let Some(wrapped) = opts.iter().find_map(|it| it.wraps.first())
+            {
+                debug!(cmd = cmd, wraps = wrapped; "writing alias");
+                self.write(format!("export alias {cmd} = ^{wrapped}\n"))?;
+            }
         }
         debug!(command_count=command_count; "wrote commands");
         Ok(())
     }
 
+    /// Write a command for each of the options of `cmd` which list the values
+    /// they take, which nu calls to complete those values. Options with the
+    /// same name share a completer, which completes all of their values.
+    fn write_completers(&mut self, cmd: &str, opts: &[CompletionLine]) -> Result<()> {
+        let mut completers: Vec<(String, Vec<String>)> = vec![];
+        for option in opts {
+            let Some(name) = completer_name(cmd, option) else {
+                continue;
+            };
+            let values = match completers.iter_mut().find(|(it, _)| *it == name) {
+                Some((_, values)) => values,
+                None => {
+                    completers.push((name, vec![]));
+                    &mut completers.last_mut().expect("just pushed").1
+                }
+            };
+            for value in option.values() {
+                if !values.contains(&value) {
+                    values.push(value);
+                }
+            }
+        }
+        for (name, values) in completers {
+            debug!(completer = name, values = as_serde!(values); "writing completer");
+            let values: Vec<_> = values.iter().map(|it| format!("{it:?}")).collect();
+            self.write(format!("def {name:?} [] {{"))?.eol()?;
+            self.indent += 1;
+            self.write(format!("[{}]", values.join(" ")))?.eol()?;
+            self.indent -= 1;
+            self.write("}")?.eol()?;
+        }
+        Ok(())
+    }
+
     /// The string necessary to indent to the current level of indentation.
     fn indent_str(&self) -> String {
         let mut cache = INDENT_CACHE.write().expect("poisoned mutex");
@@ -304,6 +1043,96 @@ impl<IO: Seek + Write> Completions<IO> {
     }
 }
 
+/// The options in `opts`, with each which defines any of the same options as
+/// an earlier one merged into it (along with the values they list), as nu
+/// rejects an extern which defines an option twice.
+fn merge_options(opts: &[CompletionLine]) -> Vec<CompletionLine> {
+    fn extend(names: &mut Vec<String>, other: &[String]) {
+        for name in other {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+    }
+    let mut merged: Vec<CompletionLine> = vec![];
+    for option in opts {
+        let Some(existing) = merged.iter_mut().find(|it| it.overlaps(option)) else {
+            merged.push(option.clone());
+            continue;
+        };
+        trace!(option = as_debug!(option); "merging option defined twice");
+        extend(&mut existing.short, &option.short);
+        extend(&mut existing.long, &option.long);
+        extend(&mut existing.old_option, &option.old_option);
+        existing.argument = match (existing.argument.take(), &option.argument) {
+            (Some(argument), Some(other)) => Some(format!("{argument} {other}")),
+            (argument, other) => argument.or_else(|| other.clone()),
+        };
+        existing.description = existing
+            .description
+            .take()
+            .or_else(|| option.description.clone());
+        existing.value_type = existing
+            .value_type
+            .take()
+            .or_else(|| option.value_type.clone());
+        existing.require_parameter |= option.require_parameter;
+        existing.exclusive |= option.exclusive;
+        extend(&mut existing.exclusions, &option.exclusions);
+    }
+    merged
+}
+
+/// The completions of each command, with those which fish only offers once
+/// one of the command's subcommands is given (`-n '__fish_seen_subcommand_from
+/// build run'`) moved to the command of each of those subcommands, as an
+/// extern has no way to say when an option applies. Any other condition can't
+/// be expressed, so those completions are left with their command.
+fn by_subcommand(completions: &completions::Completions) -> BTreeMap<String, Vec<CompletionLine>> {
+    let mut commands: BTreeMap<String, Vec<CompletionLine>> = BTreeMap::new();
+    for (cmd, opts) in completions.read().expect("rwlock read access").iter() {
+        for option in opts {
+            let subcommands = match option.condition.as_deref().map(split_shell_words) {
+                Some(Ok(words))
+                    if words.first().map(String::as_str) == Some("__fish_seen_subcommand_from") =>
+                {
+                    words[1..].to_vec()
+                }
+                _ => vec![],
+            };
+            if subcommands.is_empty() {
+                commands
+                    .entry(cmd.clone())
+                    .or_default()
+                    .push(option.clone());
+                continue;
+            }
+            for subcommand in subcommands {
+                trace!(cmd = cmd, subcommand = subcommand; "moving completion to subcommand");
+                commands
+                    .entry(format!("{cmd} {subcommand}"))
+                    .or_default()
+                    .push(CompletionLine {
+                        command: Some(format!("{cmd} {subcommand}")),
+                        condition: None,
+                        ..option.clone()
+                    });
+            }
+        }
+    }
+    commands
+}
+
+/// The name of the command which completes the values of `option` (see
+/// [`Completions::write_completers`]), if it lists any.
+fn completer_name(cmd: &str, option: &CompletionLine) -> Option<String> {
+    if !option.takes_value() || !option.old_option.is_empty() || option.values().is_empty() {
+        return None;
+    }
+    let flag = option.long.first().or_else(|| option.short.first())?;
+    Some(format!("nu-complete {cmd} {flag}"))
+}
+
 static INDENT_CACHE: LazyLock<RwLock<Vec<String>>> = LazyLock::new(|| RwLock::new(vec![]));
 
 pub(crate) static INTERNAL_COMMANDS: LazyLock<Vec<String>> = LazyLock::new(|| {
@@ -326,3 +1155,252 @@ pub(crate) static INTERNAL_COMMANDS: LazyLock<Vec<String>> = LazyLock::new(|| {
         panic!("nu help command failed")
     }
 });
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_items() {
+        let contents = "# preamble\nexport extern \"a\" [\n    --flag\n]\ndef \"nu-complete a\" [] {\n    [x y]\n}\nexport extern \"a sub\" [\n]\n";
+        assert_eq!(
+            split_items(contents),
+            vec![
+                "# preamble\n",
+                "export extern \"a\" [\n    --flag\n]\n",
+                "def \"nu-complete a\" [] {\n    [x y]\n}\n",
+                "export extern \"a sub\" [\n]\n",
+            ]
+        );
+        assert!(is_extern(split_items(contents)[1]));
+        assert!(!is_extern(split_items(contents)[2]));
+    }
+
+    #[test]
+    fn test_completer_script() {
+        assert_eq!(
+            completer_script(Path::new("/bin/nu-completions"), Path::new("/idx")),
+            "let nu_completions_fallback = $env.config.completions.external.completer?\n\
+             $env.config.completions.external.completer = {|spans|\n    \
+             let candidates = ^\"/bin/nu-completions\" complete --index \"/idx\" ...$spans | from json\n    \
+             if $candidates == null and $nu_completions_fallback != null {\n        \
+             do $nu_completions_fallback $spans\n    \
+             } else {\n        \
+             $candidates\n    \
+             }\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn test_write_bundle() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let processor = CompletionsProcessor::default();
+        for (name, contents) in [
+            (
+                "a.nu",
+                "export extern \"a\" [\n    --flag\n]\nexport extern \"git\" [\n    -v\n]\n",
+            ),
+            (
+                "b.nu",
+                "export extern \"a\" [\n    --flag\n]\nexport extern \"git\" [\n    -q\n]\n",
+            ),
+        ] {
+            let path = dir.path().join(name);
+            fs::write(&path, contents)?;
+            processor
+                .definition_files
+                .write()
+                .expect("rwlock write access")
+                .insert(path);
+        }
+        let stats = processor.write_bundle(&dir.path().join("bundle.nu"))?;
+        assert_eq!(
+            (stats.externs, stats.duplicates, stats.conflicts),
+            (2, 1, 1)
+        );
+        let bundle = fs::read_to_string(dir.path().join("bundle.nu"))?;
+        assert_eq!(bundle.matches("export extern \"git\"").count(), 1);
+        assert!(bundle.contains("    -v\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_is_sorted() -> Result<()> {
+        let completions = completions::Completions::parse(
+            [
+                "complete -c zmockery -s z",
+                "complete -c amockery -l second",
+                "complete -c amockery -l first",
+                "complete -c mmockery -s m",
+            ]
+            .into_iter(),
+        )?;
+        let mut output = Completions::new(io::Cursor::new(vec![]));
+        output.output(completions)?;
+        let output = String::from_utf8(output.io.into_inner())?;
+        assert_eq!(
+            output,
+            "export extern \"amockery\" [\n    --second\n    --first\n]\n\
+             export extern \"mmockery\" [\n    -m\n]\n\
+             export extern \"zmockery\" [\n    -z\n]\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_completers() -> Result<()> {
+        let completions = completions::Completions::parse(
+            [
+                "complete -c mockery -l color -xa 'auto always'",
+                "complete -c mockery -s c -l color -xa 'always never'",
+                "complete -c mockery -l color -n '__fish_seen_subcommand_from run' -xa 'sometimes'",
+                "complete -c mockery -s u -xa '(__fish_complete_users)'",
+                "complete -c mockery -l file -r",
+                "complete -c mockery -n '__fish_use_subcommand' -l version",
+            ]
+            .into_iter(),
+        )?;
+        let mut output = Completions::new(io::Cursor::new(vec![]));
+        output.output(completions)?;
+        let output = String::from_utf8(output.io.into_inner())?;
+        assert_eq!(
+            output,
+            "def \"nu-complete mockery color\" [] {\n    [\"auto\" \"always\" \"never\"]\n}\n\
+             export extern \"mockery\" [\n    \
+             --color(-c): string@\"nu-complete mockery color\"\n    \
+             -u: string\n    \
+             --file: string\n    \
+             --version\n]\n\
+             def \"nu-complete mockery run color\" [] {\n    [\"sometimes\"]\n}\n\
+             export extern \"mockery run\" [\n    \
+             --color: string@\"nu-complete mockery run color\"\n]\n"
+        );
+        assert_eq!(output.matches("--color(-c)").count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_clap() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("mockery.fish");
+        fs::write(
+            &path,
+            "function __fish_mockery_needs_command\n\
+             end\n\
+             complete -c mockery -n \"__fish_mockery_needs_command\" -s v -l verbose -d 'Be verbose'\n\
+             complete -c mockery -n \"__fish_mockery_needs_command\" -f -a \"build\" -d 'Build it'\n\
+             complete -c mockery -n \"__fish_mockery_using_subcommand build\" -s o -l out -r -F\n",
+        )?;
+        let completions = InputFormat::Fish.parse(&path)?;
+        let mut output = Completions::new(io::Cursor::new(vec![]));
+        output.output(completions)?;
+        let output = String::from_utf8(output.io.into_inner())?;
+        assert_eq!(
+            output,
+            "export extern \"mockery\" [\n    \
+             --verbose(-v)      # Be verbose\n]\n\
+             export extern \"mockery build\" [\n    \
+             --out(-o): string\n]\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_wrapper_aliases() -> Result<()> {
+        let completions = completions::Completions::parse(
+            ["complete -c ll --wraps 'ls -l'", "complete -c ll -s H"].into_iter(),
+        )?;
+        let mut output = Completions::new(io::Cursor::new(vec![])).with_wrapper_aliases(true);
+        output.output(completions)?;
+        let output = String::from_utf8(output.io.into_inner())?;
+        assert_eq!(
+            output,
+            "export extern \"ll\" [\n    -H\n]\n\
+             export alias ll = ^ls -l\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_help_commands_are_not_stale() {
+        let key = |command| manifest_key(Path::new(command), InputFormat::Help);
+        let fingerprint = Fingerprint::default();
+        // The first run generated a definition for `true`.
+        let mut manifest = Manifest::new(String::new());
+        for command in ["true", "nu-completions-missing-command"] {
+            manifest.entries.insert(
+                key(command),
+                Entry {
+                    fingerprint: fingerprint.clone(),
+                    definition: PathBuf::from(format!("/out/{command}.nu")),
+                    wrapped: Wrapped::new(),
+                },
+            );
+        }
+        // The second generates one for `false` instead.
+        let processor = CompletionsProcessor::default().with_manifest(manifest);
+        processor.record(
+            vec![(key("false"), fingerprint)],
+            Wrapped::new(),
+            Path::new("/out/false.nu"),
+        );
+        let manifest = processor.manifest.as_ref().expect("manifest");
+        let stale = |command| processor.is_stale(&key(command), &manifest.entries[&key(command)]);
+        assert!(key("true").is_absolute());
+        assert!(!stale("true"));
+        assert!(stale("nu-completions-missing-command"));
+    }
+
+    #[test]
+    fn test_wrappers_are_regenerated_with_wrapped_commands() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (sources, output_dir) = (dir.path().join("sources"), dir.path().join("out"));
+        fs::create_dir_all(&sources)?;
+        fs::create_dir_all(&output_dir)?;
+        let (mll, mls) = (sources.join("mll.fish"), sources.join("mls.fish"));
+        fs::write(&mll, "complete -c mll --wraps 'mls -l'\n")?;
+        fs::write(&mls, "complete -c mls -s a\n")?;
+        let run = |manifest: Manifest| -> Result<CompletionsProcessor> {
+            let processor = CompletionsProcessor::default().with_manifest(manifest);
+            processor
+                .groups
+                .write()
+                .expect("rwlock write access")
+                .extend([
+                    (OsString::from("mll.nu"), vec![mll.clone()]),
+                    (OsString::from("mls.nu"), vec![mls.clone()]),
+                ]);
+            processor.process_files_given_output_dir(&[mll.clone()], &output_dir)?;
+            processor.save_manifest(&output_dir)?;
+            Ok(processor)
+        };
+        let load = || Manifest::load(&output_dir, String::new());
+        let definition = output_dir.join("mll.nu");
+        run(Manifest::new(String::new()))?;
+        assert!(run(load())?.unchanged_definitions().contains(&definition));
+        // Only the wrapped command's completions change.
+        fs::write(&mls, "complete -c mls -s a\ncomplete -c mls -s r\n")?;
+        assert!(!run(load())?.unchanged_definitions().contains(&definition));
+        assert!(fs::read_to_string(&definition)?.contains("-r"));
+        assert!(run(load())?.unchanged_definitions().contains(&definition));
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_by_definition() {
+        let files = vec![
+            PathBuf::from("/home/user/.local/share/fish/generated_completions/mockery.fish"),
+            PathBuf::from("/home/user/.local/share/fish/generated_completions/ls.fish"),
+            PathBuf::from("/usr/share/fish/vendor_completions.d/mockery.fish"),
+            PathBuf::from("/home/user/.config/fish/completions/mockery.fish"),
+        ];
+        assert_eq!(
+            group_by_definition(files.clone(), InputFormat::Fish),
+            vec![
+                vec![files[3].clone(), files[2].clone(), files[0].clone()],
+                vec![files[1].clone()],
+            ]
+        );
+    }
+}
@@ -78,7 +78,7 @@ pub(crate) fn generate_patches(opts: &config::PatchesGenerateOptions) -> Result<
         "temp dir" = as_debug!(freshly_generated_store.path());
         "generating patches"
     );
-    let processor = CompletionsProcessor::default();
+    let processor = CompletionsProcessor::default().with_wrapper_aliases(Config::wrapper_aliases());
     let regeneration_errors: Arc<RwLock<Vec<Result<()>>>> = Default::default();
     for source in opts.sources.iter() {
         walk_dir(
@@ -89,7 +89,8 @@ pub(crate) fn generate_patches(opts: &config::PatchesGenerateOptions) -> Result<
                 let result =
                     processor.process_file_given_output_dir(&path, freshly_generated_store);
                 let freshly_generated = match result {
-                    Ok(freshly_generated) => freshly_generated,
+                    Ok(Some(freshly_generated)) => freshly_generated,
+                    Ok(None) => return Ok(()),
                     Err(err) => {
                         if Config::fail_fast() {
                             return processing_failed(source, err).map(|_| unreachable!());
@@ -102,11 +103,11 @@ pub(crate) fn generate_patches(opts: &config::PatchesGenerateOptions) -> Result<
                         }
                     }
                 };
-                let Some(file_name) = path.file_name() else {
-                    error!(path = as_debug!(path); "expected file to have filename");
-                    return Err(anyhow!("expected {path:?} to have filename"));
+                let Some(file_name) = freshly_generated.file_name() else {
+                    error!(path = as_debug!(freshly_generated); "expected file to have filename");
+                    return Err(anyhow!("expected {freshly_generated:?} to have filename"));
                 };
-                let modified_source = opts.from.join(file_name).with_extension("nu");
+                let modified_source = opts.from.join(file_name);
 
                 generate_patch(
                     &modified_source,
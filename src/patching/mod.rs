@@ -1,13 +1,14 @@
 mod fetch;
 mod generate;
 use std::{
-    path::Path,
+    collections::HashSet,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
 };
 
 use anyhow::Result;
 use beau_collector::BeauCollector as _;
-use log::{debug, error, trace, warn};
+use log::{as_debug, debug, error, trace, warn};
 
 use crate::config::Config;
 pub(crate) use fetch::fetch_latest_patch_set;
@@ -47,51 +48,73 @@ pub(crate) fn patch(source: impl AsRef<Path>, patch: impl AsRef<Path>) -> Result
         })
 }
 
-/// Pass all sources specified in [`Config::sources()`].
+/// The patch file in [`Config::patch_dir()`] which applies to the definition
+/// generated from `source`. The patch file may not exist.
+pub(crate) fn patch_file_for(source: &Path) -> Option<PathBuf> {
+    let Some(definition) = Config::input_format().definition_file_name(source) else {
+        debug!(source = source.to_string_lossy(); "failed to get file name with patch extension");
+        return None;
+    };
+    Some(Config::patch_dir().join(definition).with_extension("patch"))
+}
+
+/// Patch the definitions generated from the files in `sources`, except for
+/// those whose definitions are in `skip` (because they were already patched
+/// when they were generated by a previous run). Each definition is only
+/// patched once, even if it was merged from several sources.
 ///
 /// ## Errors:
 /// If [`Config::fail_fast()`] is true, this returns an error if any `patch`
 /// command fails. Otherwise, any errors encountered patching individual files
 /// are collected together into a single error.
-pub(crate) fn patch_all() -> Result<()> {
+pub(crate) fn patch_all(sources: &[impl AsRef<Path>], skip: &HashSet<PathBuf>) -> Result<()> {
     let mut errors = vec![Ok(())];
-    for source in Config::sources() {
-        trace!(source = source.to_string_lossy(); "checking for patches");
-        let source: &Path = source.as_ref();
-        let _patch = source.with_extension("patch");
-        let Some(patch_file) = _patch.file_name() else {
-            debug!(source = source.to_string_lossy(); "failed to get file name with patch extension");
-            continue;
+    let mut patched = HashSet::new();
+    for source in sources {
+        let files = match Config::input_format().source_files(source.as_ref()) {
+            Ok(files) => files,
+            Err(error) => {
+                debug!(source = source.as_ref().to_string_lossy(), error = as_debug!(error); "failed to list source files to patch");
+                continue;
+            }
         };
-        let patch_file = Config::patch_dir().join(patch_file);
-        if patch_file.exists() {
-            debug!(
-                patch_file = patch_file.to_string_lossy(),
-                source = source.to_string_lossy();
-                "found patch file"
-            );
-            let def = source.with_extension("nu");
-            let def = def
-                .file_name()
-                .expect("to be able to extract file name from path"); // this is already checked for
-            let def = Config::output_dir().join(def);
-            if def.exists() {
-                if let Err(error) = patch(def, patch_file) {
-                    if Config::fail_fast() {
-                        return Err(error);
-                    } else {
-                        errors.push(Err(error));
+        for source in &files {
+            trace!(source = source.to_string_lossy(); "checking for patches");
+            let Some(patch_file) = patch_file_for(source) else {
+                continue;
+            };
+            if patch_file.exists() {
+                debug!(
+                    patch_file = patch_file.to_string_lossy(),
+                    source = source.to_string_lossy();
+                    "found patch file"
+                );
+                let def = Config::input_format()
+                    .definition_file_name(source)
+                    .expect("to be able to extract file name from path"); // this is already checked for
+                let def = Config::output_dir().join(def);
+                if skip.contains(&def) {
+                    debug!(definition = def.to_string_lossy(); "definition unchanged, already patched");
+                } else if !patched.insert(def.clone()) {
+                    trace!(definition = def.to_string_lossy(); "definition already patched");
+                } else if def.exists() {
+                    if let Err(error) = patch(def, patch_file) {
+                        if Config::fail_fast() {
+                            return Err(error);
+                        } else {
+                            errors.push(Err(error));
+                        }
                     }
+                } else {
+                    warn!(
+                        source = source.to_string_lossy(),
+                        patch_file = patch_file.to_string_lossy();
+                        "source and patch found, but no converted definition. Perhaps conversion failed?"
+                    );
                 }
             } else {
-                warn!(
-                    source = source.to_string_lossy(),
-                    patch_file = patch_file.to_string_lossy();
-                    "source and patch found, but no converted definition. Perhaps conversion failed?"
-                );
+                trace!(source = source.to_string_lossy(), patch = patch_file.to_string_lossy(); "no patches found");
             }
-        } else {
-            trace!(source = source.to_string_lossy(), patch = patch_file.to_string_lossy(); "no patches found");
         }
     }
     errors.into_iter().bcollect()
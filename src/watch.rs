@@ -0,0 +1,147 @@
+//! Regenerate the definitions whenever their sources or patches change.
+use std::{
+    collections::BTreeMap,
+    env,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use log::{as_debug, debug, error, info, trace};
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use crate::{
+    config::{Config, WatchOptions},
+    generate,
+};
+
+/// `path`, relative to the current directory if it isn't absolute, so that it
+/// can be compared with the paths in the events reported by the watcher.
+fn absolute(path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::current_dir()
+            .map(|cwd| cwd.join(path))
+            .unwrap_or_else(|_| path.to_path_buf())
+    }
+}
+
+/// Whether a change to `path` affects the definitions generated from
+/// `sources` (files or directories) and the patches in `patch_dir`.
+fn is_relevant(path: &Path, sources: &[PathBuf], patch_dir: &Path) -> bool {
+    path.starts_with(patch_dir)
+        || sources
+            .iter()
+            .any(|source| path == source || (source.is_dir() && path.starts_with(source)))
+}
+
+/// The paths to watch for changes to `sources` and the patches in
+/// `patch_dir`. Individual files are watched through their directory, so
+/// that they're still watched after being replaced.
+fn watched_paths(sources: &[PathBuf], patch_dir: &Path) -> BTreeMap<PathBuf, RecursiveMode> {
+    let mut watched = BTreeMap::new();
+    for source in sources {
+        if source.is_dir() {
+            watched.insert(source.clone(), RecursiveMode::Recursive);
+        } else if let Some(parent) = source.parent() {
+            watched
+                .entry(parent.to_path_buf())
+                .or_insert(RecursiveMode::NonRecursive);
+        }
+    }
+    if patch_dir.is_dir() {
+        watched.insert(patch_dir.to_path_buf(), RecursiveMode::Recursive);
+    }
+    watched
+}
+
+/// Regenerate the definitions from those of `sources` which currently exist.
+/// Only the changed sources are reconverted and repatched (see
+/// [`crate::manifest`]).
+fn regenerate(sources: &[PathBuf]) -> Result<()> {
+    let existing: Vec<_> = sources.iter().filter(|source| source.exists()).collect();
+    info!(sources = existing.len(); "regenerating definitions");
+    if let Err(err) = generate(&existing) {
+        error!("failed to regenerate definitions: {err:?}");
+        if Config::fail_fast() {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Generate the definitions from the sources in `opts`, then watch the
+/// sources and [`Config::patch_dir()`], regenerating whenever they change.
+/// Changes are collected until none have been seen for the debounce period,
+/// so that a package upgrade only causes a single regeneration.
+pub(crate) fn watch(opts: &WatchOptions) -> Result<()> {
+    let sources: Vec<_> = Config::sources_or_discovered(&opts.sources)
+        .iter()
+        .map(|source| absolute(source.as_ref()))
+        .collect();
+    let patch_dir = absolute(Config::patch_dir());
+    let debounce = Duration::from_millis(opts.debounce);
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    for (path, mode) in watched_paths(&sources, &patch_dir) {
+        debug!(path = as_debug!(path), recursive = mode == RecursiveMode::Recursive; "watching");
+        watcher.watch(&path, mode)?;
+    }
+    regenerate(&sources)?;
+    loop {
+        let event = rx.recv()??;
+        trace!(event = as_debug!(event); "received event");
+        if matches!(event.kind, EventKind::Access(_))
+            || !event
+                .paths
+                .iter()
+                .any(|path| is_relevant(path, &sources, &patch_dir))
+        {
+            continue;
+        }
+        debug!(paths = as_debug!(event.paths); "change detected, waiting for more");
+        while let Ok(event) = rx.recv_timeout(debounce) {
+            trace!(event = as_debug!(event); "received event while debouncing");
+        }
+        regenerate(&sources)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_watched_paths() -> Result<()> {
+        let dir = tempdir()?;
+        let vendor = dir.path().join("vendor_completions.d");
+        let generated = dir.path().join("generated_completions");
+        let patches = dir.path().join("patches");
+        for it in [&vendor, &generated, &patches] {
+            fs::create_dir(it)?;
+        }
+        let sources = vec![vendor.clone(), generated.join("mockery.fish")];
+        let watched = watched_paths(&sources, &patches);
+        assert_eq!(
+            watched.into_iter().collect::<Vec<_>>(),
+            vec![
+                (generated.clone(), RecursiveMode::NonRecursive),
+                (patches.clone(), RecursiveMode::Recursive),
+                (vendor.clone(), RecursiveMode::Recursive),
+            ]
+        );
+        assert!(is_relevant(&vendor.join("git.fish"), &sources, &patches));
+        assert!(is_relevant(
+            &generated.join("mockery.fish"),
+            &sources,
+            &patches
+        ));
+        assert!(is_relevant(&patches.join("git.patch"), &sources, &patches));
+        assert!(!is_relevant(&generated.join("ls.fish"), &sources, &patches));
+        Ok(())
+    }
+}